@@ -1,229 +1,1089 @@
-use std::time::Duration;
+use std::{
+    collections::VecDeque,
+    fs::File,
+    future::Future,
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
 
+use anyhow::Result;
 use async_std::{
-    channel::{self, Sender},
+    channel::{self, Receiver, Sender, TrySendError},
     future::pending,
     prelude::FutureExt as AsyncStdFutureExt,
 };
+use chrono::{DateTime, Local, Utc};
+use nix::{
+    errno::Errno,
+    fcntl::{flock, FlockArg},
+};
+use rand::Rng;
 use url::Url;
 
 use crate::{
-    cmd_utils::{get_folder_size, Duplicity, Rclone},
-    config::BackupConfig,
+    cmd_utils::{
+        get_folder_size, get_free_space, get_region_checksums, get_region_files,
+        load_region_checksums, save_region_checksums, Duplicity, Rclone, RegionChecksums,
+        RegionFiles, Snapshot,
+    },
+    config::{BackupConfig, BackupTarget},
+    control::SharedStatus,
+    mail::{send_report, send_warning, ReportChannel},
     rcon::MinecraftCommand,
 };
 
+const LOCK_FILE_NAME: &str = ".server-manager.lock";
+
+// Kept in the server folder, like the lock file, so that managers of other
+// servers do not share them
+const REGION_CHECKSUMS: &str = "region_checksums.txt";
+const LAST_BACKUP: &str = "last_backup.txt";
+
+// Replies of the server to save-off, as of different Minecraft versions
+const SAVE_OFF_CONFIRMATIONS: [&str; 3] = [
+    "automatic saving is now disabled",
+    "saving is already turned off",
+    "turned off world auto-saving",
+];
+const SAVE_OFF_ATTEMPTS: u32 = 3;
+
+// Leaves the server time to start before catching up on a missed backup
+const CATCH_UP_DELAY: Duration = Duration::from_secs(5 * 60);
+
+// Durations are averaged so that a single backup slowed down by unrelated load
+// does not raise a warning
+const DURATION_SAMPLES: usize = 5;
+
+struct BackupLock(File);
+
+impl BackupLock {
+    // Returns None if the lock is currently held by another backup.
+    fn acquire(folder: &Path) -> Result<Option<Self>> {
+        let file = File::create(folder.join(LOCK_FILE_NAME))?;
+        match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => Ok(Some(Self(file))),
+            Err(Errno::EWOULDBLOCK) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+impl Drop for BackupLock {
+    fn drop(&mut self) {
+        flock(self.0.as_raw_fd(), FlockArg::Unlock).ok();
+    }
+}
+
+// Where backups are stored, prepared once for all backups
+struct Targets {
+    world_folder: String,
+    backup_folder_url: String,
+    lock_folder: PathBuf,
+    local_folder: Option<String>,
+    // Server folder, backup URL and duplicity arguments for the extra paths
+    extra: Option<(String, String, Vec<String>)>,
+}
+
+impl Targets {
+    fn new(config: &BackupConfig) -> Result<Self, String> {
+        let world_folder = match config.world_folder.clone().into_os_string().into_string() {
+            Ok(p) => p,
+            Err(_) => {
+                return Err("[BACKUP] Failed to convert world path to string.".into());
+            }
+        };
+
+        let (backup_folder_url, local_folder) = match &config.backup_target {
+            BackupTarget::Local(folder) => {
+                let folder = match folder.to_str() {
+                    Some(p) => p.to_string(),
+                    None => {
+                        return Err("[BACKUP] Failed to convert backup path to string.".into());
+                    }
+                };
+
+                let url = match Url::from_file_path(&folder) {
+                    Ok(p) => p.to_string(),
+                    Err(_) => {
+                        return Err("[BACKUP] Failed to make path of world folder.".into());
+                    }
+                };
+
+                (url, Some(folder))
+            }
+            BackupTarget::Remote(url) => (url.clone(), None),
+        };
+
+        // Extra paths are backed up from the server folder as a separate
+        // duplicity target, only including the requested paths.
+        let extra = if config.extra_backup_paths.is_empty() {
+            None
+        } else {
+            let server_folder = match config.server_folder.to_str() {
+                Some(p) => p.to_string(),
+                None => {
+                    return Err("[BACKUP] Failed to convert server path to string.".into());
+                }
+            };
+
+            let extra_folder_url = match &local_folder {
+                Some(folder) => {
+                    let extra_folder = Path::new(folder).join("extra");
+                    if std::fs::create_dir_all(&extra_folder).is_err() {
+                        return Err("[BACKUP] Failed to create extra backup folder.".into());
+                    }
+
+                    match Url::from_file_path(&extra_folder) {
+                        Ok(p) => p.to_string(),
+                        Err(_) => {
+                            return Err(
+                                "[BACKUP] Failed to make path of extra backup folder.".into()
+                            );
+                        }
+                    }
+                }
+                None => format!("{}/extra", backup_folder_url),
+            };
+
+            let mut args = config.duplicity_extra_args.clone();
+            for path in &config.extra_backup_paths {
+                args.push("--include".into());
+                args.push(path.to_string_lossy().into_owned());
+            }
+            args.push("--exclude".into());
+            args.push("**".into());
+
+            Some((server_folder, extra_folder_url, args))
+        };
+
+        Ok(Self {
+            world_folder,
+            backup_folder_url,
+            // Kept out of the backup folder, so that it is not synced with
+            // rclone nor compared by rclone check
+            lock_folder: config.server_folder.clone(),
+            local_folder,
+            extra,
+        })
+    }
+}
+
+// Marks a backup as running in the manager status for as long as it is alive,
+// including when the backup fails midway
+struct RunningBackup<'a>(&'a SharedStatus);
+
+impl<'a> RunningBackup<'a> {
+    fn start(status: &'a SharedStatus) -> Self {
+        if let Ok(mut status) = status.lock() {
+            status.backup_running = true;
+        }
+        Self(status)
+    }
+}
+
+impl Drop for RunningBackup<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut status) = self.0.lock() {
+            status.backup_running = false;
+        }
+    }
+}
+
+#[derive(Default)]
+struct BackupDurations {
+    samples: VecDeque<Duration>,
+    // Whether the average was already longer than the backup interval
+    slow: bool,
+}
+
+impl BackupDurations {
+    // Returns the new average duration
+    fn record(&mut self, duration: Duration) -> Duration {
+        if self.samples.len() == DURATION_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(duration);
+
+        self.samples.iter().sum::<Duration>() / self.samples.len() as u32
+    }
+}
+
+// Backups requested through the control socket
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BackupRequest {
+    Now,
+    // Starts a new backup chain, whatever the age of the last full backup
+    Full,
+}
+
 pub struct BackupManager;
 
 impl BackupManager {
+    // Randomized so that managers sharing storage do not all back up at once
+    fn next_delay(config: &BackupConfig) -> Duration {
+        let jitter = config.backup_jitter.as_secs() as i64;
+        if jitter == 0 {
+            return config.incremental;
+        }
+
+        let offset = rand::thread_rng().gen_range(-jitter..=jitter);
+        Duration::from_secs((config.incremental.as_secs() as i64 + offset) as u64)
+    }
+
     pub async fn start(
         config: Option<BackupConfig>,
         cmd_chan: Sender<MinecraftCommand>,
+        trigger: Receiver<BackupRequest>,
+        status: SharedStatus,
+        reports: Vec<ReportChannel>,
     ) -> Vec<String> {
         if let Some(config) = config {
-            let (back_send, back_rec) = channel::bounded(1);
+            let targets = match Targets::new(&config) {
+                Ok(targets) => targets,
+                Err(x) => return vec![x],
+            };
 
-            let world_folder = match config.world_folder.into_os_string().into_string() {
-                Ok(p) => p,
-                Err(_) => {
-                    return vec!["[BACKUP] Failed to convert world path to string.".into()];
+            let mut durations = BackupDurations::default();
+            // Backups are also made when the server restarts more often than
+            // the interval between them
+            let mut first_delay = config
+                .initial_backup_delay
+                .unwrap_or_else(|| Self::next_delay(&config));
+            if config.catch_up_backups && Self::backup_missed(&config) {
+                first_delay = first_delay.min(CATCH_UP_DELAY);
+                info!(
+                    "[BACKUP] A scheduled backup was missed, backing up in {} minutes.",
+                    first_delay.as_secs() / 60
+                );
+            }
+            let mut waiter = Self::schedule(&status, first_delay);
+            loop {
+                // Backups requested through the control socket skip the wait
+                let requested = async {
+                    waiter.await;
+                    None
                 }
-            };
+                .race(async {
+                    match trigger.recv().await {
+                        Ok(request) => Some(request),
+                        Err(_) => pending().await,
+                    }
+                })
+                .await;
+                waiter = Self::schedule(&status, Self::next_delay(&config));
 
-            let backup_folder = match config.backup_folder.into_os_string().into_string() {
-                Ok(p) => p,
-                Err(_) => {
-                    return vec!["[BACKUP] Failed to convert backup path to string.".into()];
+                // Only scheduled backups are paused, not requested ones
+                let paused = status.lock().is_ok_and(|status| status.backups_paused());
+                if paused && requested.is_none() {
+                    info!("[BACKUP] Backups paused, skipping.");
+                    continue;
                 }
-            };
 
-            let backup_folder_url = match Url::from_file_path(&backup_folder) {
-                Ok(p) => p,
-                Err(_) => {
-                    return vec!["[BACKUP] Failed to make path of world folder.".into()];
+                // The server generates the world on its first start, backups
+                // start once it did
+                if !Path::new(&targets.world_folder).is_dir() {
+                    info!("[BACKUP] The world folder does not exist yet, skipping this backup.");
+                    continue;
                 }
-            };
 
-            let mut waiter = async_std::task::sleep(config.incremental);
-            loop {
-                waiter.await;
-                waiter = async_std::task::sleep(config.incremental);
+                // Held until the end of the cycle so that backups never overlap,
+                // even with another manager using the same backup folder
+                let _lock = match BackupLock::acquire(&targets.lock_folder) {
+                    Ok(Some(lock)) => lock,
+                    Ok(None) => {
+                        warn!("[BACKUP] Another backup is still running, skipping this one.");
+                        continue;
+                    }
+                    Err(x) => {
+                        Self::count_failure(&status);
+                        let err_log =
+                            vec![format!("[BACKUP] Failed to lock backup folder:\n{}", x)];
+                        if config.backup_failure_fatal {
+                            return err_log;
+                        }
+
+                        Self::report_failure(&reports, err_log).await;
+                        continue;
+                    }
+                };
+
+                // Backups that would fill the disk are not worth stopping the server
+                if let Err(msg) = Self::check_free_space(&config, &targets) {
+                    Self::count_failure(&status);
+                    error!("{}", msg);
+                    send_report(&reports, vec![msg], false, Utc::now()).await;
+                    continue;
+                }
 
-                println!("[ServerManager] [BACKUP] Sarting backup...");
+                // Sent whatever `silent` is, as it is configured on its own
+                if let Some(warning) = config.backup_warning {
+                    let msg = format!("Backup in {} seconds, expect brief lag.", warning.as_secs());
+                    if let Err(TrySendError::Full(_)) =
+                        cmd_chan.try_send(MinecraftCommand::Broadcast(msg))
+                    {
+                        warn!("[BACKUP] Command queue is full, dropping warning message.");
+                    }
+                    async_std::task::sleep(warning).await;
+                }
+
+                let full = requested == Some(BackupRequest::Full);
+                if full {
+                    info!("[BACKUP] Sarting full backup...");
+                } else {
+                    info!("[BACKUP] Sarting backup...");
+                }
+                let _running = RunningBackup::start(&status);
+                let started = Instant::now();
+
+                let cycle = Self::cycle(&config, &targets, &cmd_chan, &status, &reports, full);
+                let region_files = match config.max_backup_duration {
+                    Some(max_duration) => match cycle.timeout(max_duration).await {
+                        Ok(res) => res,
+                        Err(_) => {
+                            Self::count_failure(&status);
+                            Self::abort(&config, &targets, &cmd_chan, &reports).await;
+                            continue;
+                        }
+                    },
+                    None => cycle.await,
+                };
+
+                let region_files = match region_files {
+                    Ok(region_files) => region_files,
+                    Err(err_log) => {
+                        Self::count_failure(&status);
+                        if config.backup_failure_fatal {
+                            return err_log;
+                        }
+
+                        Self::recover(&config, &targets, &cmd_chan).await;
+                        Self::report_failure(&reports, err_log).await;
+                        continue;
+                    }
+                };
+
+                // Not counted in the backup duration, as the server saves again
+                if let Some(files) = region_files {
+                    Self::verify_region_checksums(&config, &targets.world_folder, &files, &reports)
+                        .await;
+                }
+
+                if let Ok(mut status) = status.lock() {
+                    status.last_backup = Some(Utc::now());
+                }
+                Self::record_backup(&config);
+
+                let average = durations.record(started.elapsed());
+                if average > config.incremental {
+                    let msg = format!(
+                        "[BACKUP] Backups take {} minutes on average, longer than the {} minutes between them. Consider making them less frequent.",
+                        average.as_secs() / 60,
+                        config.incremental.as_secs() / 60
+                    );
+                    warn!("{}", msg);
+
+                    // Only notified once until backups are fast enough again
+                    if config.notify_slow_backups && !durations.slow {
+                        send_report(&reports, vec![msg], false, Utc::now()).await;
+                    }
+                    durations.slow = true;
+                } else {
+                    durations.slow = false;
+                }
+            }
+        } else {
+            pending::<()>().await;
+            unreachable!()
+        }
+    }
 
-                if !config.silent {
+    // Waits for the next scheduled backup, which is published in the status
+    fn schedule(status: &SharedStatus, delay: Duration) -> impl Future<Output = ()> {
+        if let Ok(mut status) = status.lock() {
+            status.next_backup = chrono::Duration::from_std(delay)
+                .ok()
+                .map(|delay| Utc::now() + delay);
+        }
+
+        async_std::task::sleep(delay)
+    }
+
+    // Unknown without a recorded backup, in which case nothing is missed
+    fn backup_missed(config: &BackupConfig) -> bool {
+        let last = match std::fs::read_to_string(config.server_folder.join(LAST_BACKUP)) {
+            Ok(last) => last,
+            Err(_) => return false,
+        };
+
+        match DateTime::parse_from_rfc3339(last.trim()) {
+            Ok(last) => (Utc::now() - last.with_timezone(&Utc))
+                .to_std()
+                .is_ok_and(|age| age >= config.incremental),
+            Err(err) => {
+                warn!("[BACKUP] Failed to read {}:\n{}", LAST_BACKUP, err);
+                false
+            }
+        }
+    }
+
+    // Dry runs make no backup, so they are not recorded
+    fn record_backup(config: &BackupConfig) {
+        if config.dry_run {
+            return;
+        }
+
+        let path = config.server_folder.join(LAST_BACKUP);
+        if let Err(err) = std::fs::write(path, Utc::now().to_rfc3339()) {
+            warn!("[BACKUP] Failed to write {}:\n{}", LAST_BACKUP, err);
+        }
+    }
+
+    fn count_failure(status: &SharedStatus) {
+        if let Ok(mut status) = status.lock() {
+            status.backup_failures += 1;
+        }
+    }
+
+    fn check_free_space(config: &BackupConfig, targets: &Targets) -> Result<(), String> {
+        let min_free_space = match config.min_free_space_gb {
+            Some(gb) => gb * 1024 * 1024 * 1024,
+            None => return Ok(()),
+        };
+
+        let mut folders = vec![("world", targets.world_folder.as_str())];
+        if let Some(local_folder) = &targets.local_folder {
+            folders.push(("backup", local_folder));
+        }
+
+        for (name, folder) in folders {
+            match get_free_space(folder) {
+                Ok(free) if free < min_free_space => {
+                    return Err(format!(
+                        "[BACKUP] Skipping backup, only {:.2} GB are free for the {} folder.",
+                        free as f64 / (1024.0 * 1024.0 * 1024.0),
+                        name
+                    ));
+                }
+                Ok(_) => (),
+                Err(x) => warn!(
+                    "[BACKUP] Failed to check free space of the {} folder:\n{}",
+                    name, x
+                ),
+            }
+        }
+
+        Ok(())
+    }
+
+    // The processes of the aborted backup were killed when it was dropped, so
+    // only saving and snapshots are left to clean up.
+    async fn abort(
+        config: &BackupConfig,
+        targets: &Targets,
+        cmd_chan: &Sender<MinecraftCommand>,
+        reports: &[ReportChannel],
+    ) {
+        let minutes = config.max_backup_duration.unwrap_or_default().as_secs() / 60;
+        let msg = format!(
+            "[BACKUP] Backup took more than {} minutes and was aborted.",
+            minutes
+        );
+        error!("{}", msg);
+
+        Self::recover(config, targets, cmd_chan).await;
+        send_report(reports, vec![msg], false, Utc::now()).await;
+    }
+
+    // Leaves the server as it was before a backup that did not complete
+    async fn recover(
+        config: &BackupConfig,
+        targets: &Targets,
+        cmd_chan: &Sender<MinecraftCommand>,
+    ) {
+        match config.snapshot_mode {
+            Some(mode) => {
+                Snapshot::delete(mode, &targets.world_folder).await.ok();
+            }
+            None if config.save_strategy.disables_saving() => {
+                let enabled = cmd_chan
+                    .send(MinecraftCommand::SaveOn)
+                    .timeout(Duration::from_secs(10))
+                    .await;
+                if !matches!(enabled, Ok(Ok(()))) {
+                    warn!("[BACKUP] Failed to enable saving again after incomplete backup.");
+                }
+            }
+            None => (),
+        }
+    }
+
+    async fn report_failure(reports: &[ReportChannel], err_log: Vec<String>) {
+        for e in &err_log {
+            error!("{}", e);
+        }
+        warn!(
+            "[BACKUP] Keeping the server running, the next backup will be attempted as scheduled."
+        );
+
+        send_report(reports, err_log, false, Utc::now()).await;
+    }
+
+    async fn cycle(
+        config: &BackupConfig,
+        targets: &Targets,
+        cmd_chan: &Sender<MinecraftCommand>,
+        status: &SharedStatus,
+        reports: &[ReportChannel],
+        full: bool,
+    ) -> Result<Option<RegionFiles>, Vec<String>> {
+        if !config.silent {
+            // Broadcasts are not worth an emergency shutdown, so they are
+            // dropped rather than waited on when the command queue is full.
+            match cmd_chan.try_send(MinecraftCommand::Broadcast("Backup started.".into())) {
+                Err(TrySendError::Full(_)) => {
+                    warn!("[BACKUP] Command queue is full, dropping start message.");
+                }
+                Err(TrySendError::Closed(_)) => {
+                    return Err(vec!["[BACKUP] Failed to broadcast start message.".into()]);
+                }
+                _ => (),
+            }
+        }
+
+        let backup_source = if let Some(mode) = config.snapshot_mode {
+            // Snapshots are consistent without disabling saving, but recent
+            // chunks are only in them once saved
+            if config.save_strategy.saves() {
+                Self::save_world(config, cmd_chan).await?;
+            }
+
+            match Snapshot::create(mode, &targets.world_folder).await {
+                Ok(path) => path,
+                Err(x) => {
+                    return Err(vec![format!("[BACKUP] Failed to snapshot world:\n{}", x)]);
+                }
+            }
+        } else {
+            if config.save_strategy.disables_saving() {
+                if config.confirm_save_off {
+                    Self::confirm_save_off(cmd_chan).await?;
+                } else {
                     match cmd_chan
-                        .send(MinecraftCommand::Broadcast("Backup started.".into()))
+                        .send(MinecraftCommand::SaveOff(None))
                         .timeout(Duration::from_secs(10))
                         .await
                     {
                         Err(_) => {
-                            return vec![
-                                "[BACKUP] Timed out while broadcasting start message.".into()
-                            ];
+                            return Err(vec![
+                                "[BACKUP] Timed out while requesting to disable saving.".into(),
+                            ]);
                         }
                         Ok(Err(_)) => {
-                            return vec!["[BACKUP] Failed to broadcast start message.".into()];
+                            return Err(vec!["[BACKUP] Failed to disable saving.".into()]);
                         }
                         _ => (),
                     }
                 }
+            }
 
-                match cmd_chan
-                    .send(MinecraftCommand::SaveOff)
-                    .timeout(Duration::from_secs(10))
-                    .await
-                {
-                    Err(_) => {
-                        return vec![
-                            "[BACKUP] Timed out while requesting to disable saving.".into()
-                        ];
-                    }
-                    Ok(Err(_)) => {
-                        return vec!["[BACKUP] Failed to disable saving.".into()];
-                    }
-                    _ => (),
+            if config.save_strategy.saves() {
+                Self::save_world(config, cmd_chan).await?;
+            }
+
+            targets.world_folder.clone()
+        };
+
+        let backup_res = Self::duplicity_backup(
+            config,
+            &backup_source,
+            &targets.backup_folder_url,
+            &Self::world_args(config, &backup_source),
+            full,
+        )
+        .await;
+
+        // Listed while the world is still as it was backed up, and only read
+        // once saving is enabled again, unless it was backed up from a snapshot
+        let mut region_files = None;
+        if backup_res.is_ok() && config.verify_region_checksums {
+            match get_region_files(&backup_source).await {
+                Ok(files) if config.snapshot_mode.is_some() => {
+                    Self::verify_region_checksums(config, &backup_source, &files, reports).await;
                 }
+                Ok(files) => region_files = Some(files),
+                Err(err) => warn!("[BACKUP] Failed to list region files:\n{}", err),
+            }
+        }
 
-                match cmd_chan
-                    .send(MinecraftCommand::SaveAll(config.flush_on_save))
-                    .timeout(Duration::from_secs(10))
-                    .await
-                {
-                    Err(_) => {
-                        return vec!["[BACKUP] Timed out while requesting save.".into()];
-                    }
-                    Ok(Err(_)) => {
-                        return vec!["[BACKUP] Failed to save.".into()];
-                    }
-                    _ => (),
+        if let Some(mode) = config.snapshot_mode {
+            if let Err(x) = Snapshot::delete(mode, &targets.world_folder).await {
+                return Err(vec![format!(
+                    "[BACKUP] Failed to delete world snapshot:\n{}",
+                    x
+                )]);
+            }
+        }
+
+        if let Err(x) = backup_res {
+            return Err(vec![format!(
+                "[BACKUP] Failed to perform duplicity backup:\n{}",
+                x
+            )]);
+        }
+
+        if config.snapshot_mode.is_none() && config.save_strategy.disables_saving() {
+            match cmd_chan
+                .send(MinecraftCommand::SaveOn)
+                .timeout(Duration::from_secs(10))
+                .await
+            {
+                Err(_) => {
+                    return Err(vec![
+                        "[BACKUP] Timed out while requesting to disable saving.".into(),
+                    ]);
+                }
+                Ok(Err(_)) => {
+                    return Err(vec!["[BACKUP] Failed to disable saving.".into()]);
                 }
+                _ => (),
+            }
+        }
+
+        if let Err(x) = Self::backup_extra(config, targets, full).await {
+            return Err(vec![x]);
+        }
+
+        info!("[BACKUP] Backup complete.");
+
+        if !config.silent {
+            let backup_msg = if let Ok(folder_size) = get_folder_size(&targets.world_folder).await {
+                format!(
+                    "Backup done! ({:.2} GB)",
+                    folder_size as f64 / (1024u64.pow(3) as f64)
+                )
+            } else {
+                "Backup done! (failed to get size)".into()
+            };
+
+            match cmd_chan.try_send(MinecraftCommand::Broadcast(backup_msg)) {
+                Err(TrySendError::Full(_)) => {
+                    warn!("[BACKUP] Command queue is full, dropping completion message.");
+                }
+                Err(TrySendError::Closed(_)) => {
+                    return Err(vec![
+                        "[BACKUP] Failed to broadcast completion message.".into()
+                    ]);
+                }
+                _ => (),
+            }
+        }
+
+        if let Err(x) = Self::cleanup_and_sync(config, targets, Some(status)).await {
+            return Err(vec![x]);
+        }
+
+        Ok(region_files)
+    }
+
+    // Asks the server to save the world and waits for it to be written
+    async fn save_world(
+        config: &BackupConfig,
+        cmd_chan: &Sender<MinecraftCommand>,
+    ) -> Result<(), Vec<String>> {
+        let (back_send, back_rec) = channel::bounded(1);
+        let (resp_send, resp_rec) = channel::bounded(1);
+
+        if let Some(poll_timeout) = config.save_poll_timeout {
+            let deadline = Instant::now() + poll_timeout;
+            loop {
+                while resp_rec.try_recv().is_ok() {}
 
                 match cmd_chan
-                    .send(MinecraftCommand::Await(back_send.clone()))
+                    .send(MinecraftCommand::Raw(
+                        config.save_command.clone(),
+                        resp_send.clone(),
+                    ))
                     .timeout(Duration::from_secs(10))
                     .await
                 {
                     Err(_) => {
-                        return vec![
-                            "[BACKUP] Timed out while requesting to send await handle.".into()
-                        ];
+                        return Err(vec!["[BACKUP] Timed out while requesting save.".into()]);
                     }
                     Ok(Err(_)) => {
-                        return vec!["[BACKUP] Failed to send await handle.".into()];
+                        return Err(vec!["[BACKUP] Failed to save.".into()]);
                     }
                     _ => (),
                 }
 
-                match back_rec.recv().timeout(Duration::from_secs(2 * 60)).await {
+                let response = match resp_rec.recv().timeout(Duration::from_secs(2 * 60)).await {
                     Err(_) => {
-                        return vec!["[BACKUP] Timed out while waiting for backup.".into()];
+                        return Err(vec!["[BACKUP] Timed out while waiting for save.".into()]);
                     }
                     Ok(Err(_)) => {
-                        return vec!["[BACKUP] Failed to wait for save completion.".into()];
+                        return Err(vec!["[BACKUP] Failed to wait for save completion.".into()]);
                     }
-                    _ => (),
+                    Ok(Ok(response)) => response,
+                };
+
+                if response.to_lowercase().contains("saved the game") {
+                    break;
                 }
 
-                if !config.flush_on_save {
-                    async_std::task::sleep(Duration::from_secs(2 * 60)).await;
+                if Instant::now() > deadline {
+                    return Err(vec![
+                        "[BACKUP] Server did not report save completion in time.".into(),
+                    ]);
                 }
 
-                if let Err(x) = Duplicity::backup(
-                    config.full_backup_every,
-                    &world_folder,
-                    backup_folder_url.as_str(),
-                )
+                async_std::task::sleep(Duration::from_secs(2)).await;
+            }
+        } else {
+            match cmd_chan
+                .send(MinecraftCommand::SaveAll(config.flush_on_save))
+                .timeout(Duration::from_secs(10))
                 .await
-                {
-                    return vec![format!(
-                        "[BACKUP] Failed to perform duplicity backup:\n{}",
-                        x
-                    )];
+            {
+                Err(_) => {
+                    return Err(vec!["[BACKUP] Timed out while requesting save.".into()]);
+                }
+                Ok(Err(_)) => {
+                    return Err(vec!["[BACKUP] Failed to save.".into()]);
                 }
+                _ => (),
+            }
 
-                match cmd_chan
-                    .send(MinecraftCommand::SaveOn)
-                    .timeout(Duration::from_secs(10))
-                    .await
-                {
-                    Err(_) => {
-                        return vec![
-                            "[BACKUP] Timed out while requesting to disable saving.".into()
-                        ];
-                    }
-                    Ok(Err(_)) => {
-                        return vec!["[BACKUP] Failed to disable saving.".into()];
-                    }
-                    _ => (),
+            match cmd_chan
+                .send(MinecraftCommand::Await(back_send.clone()))
+                .timeout(Duration::from_secs(10))
+                .await
+            {
+                Err(_) => {
+                    return Err(vec![
+                        "[BACKUP] Timed out while requesting to send await handle.".into(),
+                    ]);
+                }
+                Ok(Err(_)) => {
+                    return Err(vec!["[BACKUP] Failed to send await handle.".into()]);
                 }
+                _ => (),
+            }
 
-                println!("[ServerManager] [BACKUP] Backup complete.");
+            match back_rec.recv().timeout(Duration::from_secs(2 * 60)).await {
+                Err(_) => {
+                    return Err(vec!["[BACKUP] Timed out while waiting for backup.".into()]);
+                }
+                Ok(Err(_)) => {
+                    return Err(vec!["[BACKUP] Failed to wait for save completion.".into()]);
+                }
+                _ => (),
+            }
 
-                if !config.silent {
-                    let backup_msg = if let Ok(folder_size) = get_folder_size(&world_folder).await {
-                        format!(
-                            "Backup done! ({:.2} GB)",
-                            folder_size as f64 / (1024u64.pow(3) as f64)
-                        )
-                    } else {
-                        "Backup done! (failed to get size)".into()
-                    };
+            if !config.flush_on_save {
+                async_std::task::sleep(Duration::from_secs(2 * 60)).await;
+            }
+        }
 
-                    match cmd_chan
-                        .send(MinecraftCommand::Broadcast(backup_msg))
-                        .timeout(Duration::from_secs(10))
-                        .await
-                    {
-                        Err(_) => {
-                            return vec![
-                                "[BACKUP] Timed out while broadcasting start message.".into()
-                            ];
-                        }
-                        Ok(Err(_)) => {
-                            return vec!["[BACKUP] Failed to broadcast start message.".into()];
-                        }
-                        _ => (),
-                    }
+        Ok(())
+    }
+
+    // A lagging server may not have processed save-off yet when the backup
+    // would start, so its acknowledgement is awaited instead
+    async fn confirm_save_off(cmd_chan: &Sender<MinecraftCommand>) -> Result<(), Vec<String>> {
+        let (resp_send, resp_rec) = channel::bounded(1);
+        let mut response = String::new();
+        for attempt in 0..SAVE_OFF_ATTEMPTS {
+            if attempt > 0 {
+                async_std::task::sleep(Duration::from_secs(2)).await;
+            }
+
+            match cmd_chan
+                .send(MinecraftCommand::SaveOff(Some(resp_send.clone())))
+                .timeout(Duration::from_secs(10))
+                .await
+            {
+                Err(_) => {
+                    return Err(vec![
+                        "[BACKUP] Timed out while requesting to disable saving.".into(),
+                    ]);
                 }
+                Ok(Err(_)) => {
+                    return Err(vec!["[BACKUP] Failed to disable saving.".into()]);
+                }
+                _ => (),
+            }
 
-                if let Err(x) =
-                    Duplicity::cleanup_old(config.keep_full_backup, backup_folder_url.as_str())
-                        .await
-                {
-                    return vec![format!(
-                        "[BACKUP] Failed to perform duplicity cleanup:\n{}",
+            response = match resp_rec.recv().timeout(Duration::from_secs(30)).await {
+                Err(_) => continue,
+                Ok(Err(_)) => {
+                    return Err(vec![
+                        "[BACKUP] Failed to wait for save-off confirmation.".into()
+                    ]);
+                }
+                Ok(Ok(response)) => response,
+            };
+
+            let lower = response.to_lowercase();
+            if SAVE_OFF_CONFIRMATIONS.iter().any(|c| lower.contains(c)) {
+                return Ok(());
+            }
+        }
+
+        if response.is_empty() {
+            return Err(vec![
+                "[BACKUP] Server did not confirm that saving is disabled.".into(),
+            ]);
+        }
+
+        Err(vec![format!(
+            "[BACKUP] Server did not confirm that saving is disabled, last response:\n{}",
+            response
+        )])
+    }
+
+    // Region files are only rewritten by the server when their chunks are
+    // saved, which also updates their modification time. A file whose content
+    // changed while its size and modification time did not is therefore
+    // likely corrupted, rather than legitimately modified.
+    async fn verify_region_checksums(
+        config: &BackupConfig,
+        world_folder: &str,
+        files: &RegionFiles,
+        reports: &[ReportChannel],
+    ) {
+        let checksums = match get_region_checksums(world_folder, files).await {
+            Ok(checksums) => checksums,
+            Err(err) => {
+                warn!("[BACKUP] Failed to compute region checksums:\n{}", err);
+                return;
+            }
+        };
+
+        let path = config.server_folder.join(REGION_CHECKSUMS);
+        let previous = if path.exists() {
+            match load_region_checksums(&path) {
+                Ok(previous) => previous,
+                Err(err) => {
+                    warn!(
+                        "[BACKUP] Failed to read {}, starting over:\n{}",
+                        REGION_CHECKSUMS, err
+                    );
+                    RegionChecksums::new()
+                }
+            }
+        } else {
+            RegionChecksums::new()
+        };
+
+        if let Err(err) = save_region_checksums(&path, &checksums) {
+            warn!("[BACKUP] Failed to write {}:\n{}", REGION_CHECKSUMS, err);
+        }
+
+        let suspicious: Vec<&str> = checksums
+            .iter()
+            .filter(|(file, entry)| {
+                previous.get(*file).is_some_and(|old| {
+                    old.checksum != entry.checksum
+                        && old.modified == entry.modified
+                        && old.len == entry.len
+                })
+            })
+            .map(|(file, _)| file.as_str())
+            .collect();
+
+        if suspicious.is_empty() {
+            return;
+        }
+
+        let message = format!(
+            "[BACKUP] {} region files changed without being saved by the server, they may be corrupted. Earlier backups may still hold intact copies:\n{}",
+            suspicious.len(),
+            suspicious.join("\n")
+        );
+        warn!("{}", message);
+        send_warning(reports, message, Utc::now()).await;
+    }
+
+    // Backs up the world once without a running server, so without having to
+    // disable saving.
+    pub async fn backup_once(config: &BackupConfig, full: bool) -> Result<(), String> {
+        let targets = Targets::new(config)?;
+
+        if !Path::new(&targets.world_folder).is_dir() {
+            return Err(format!(
+                "[BACKUP] The world folder {} does not exist yet, nothing to back up.",
+                targets.world_folder
+            ));
+        }
+
+        let _lock = match BackupLock::acquire(&targets.lock_folder) {
+            Ok(Some(lock)) => lock,
+            Ok(None) => return Err("[BACKUP] Another backup is still running.".into()),
+            Err(x) => return Err(format!("[BACKUP] Failed to lock backup folder:\n{}", x)),
+        };
+
+        Self::check_free_space(config, &targets)?;
+
+        info!("[BACKUP] Sarting backup...");
+
+        if let Err(x) = Self::duplicity_backup(
+            config,
+            &targets.world_folder,
+            &targets.backup_folder_url,
+            &Self::world_args(config, &targets.world_folder),
+            full,
+        )
+        .await
+        {
+            return Err(format!(
+                "[BACKUP] Failed to perform duplicity backup:\n{}",
+                x
+            ));
+        }
+
+        Self::backup_extra(config, &targets, full).await?;
+        info!("[BACKUP] Backup complete.");
+
+        Self::cleanup_and_sync(config, &targets, None).await?;
+        Self::record_backup(config);
+        Ok(())
+    }
+
+    async fn duplicity_backup(
+        config: &BackupConfig,
+        to_backup: &str,
+        backup_to: &str,
+        extra_args: &[String],
+        full: bool,
+    ) -> Result<()> {
+        if full {
+            Duplicity::full_backup(config, to_backup, backup_to, extra_args).await
+        } else {
+            Duplicity::backup(config, to_backup, backup_to, extra_args).await
+        }
+    }
+
+    async fn backup_extra(
+        config: &BackupConfig,
+        targets: &Targets,
+        full: bool,
+    ) -> Result<(), String> {
+        if let Some((server_folder, extra_folder_url, args)) = &targets.extra {
+            if let Err(x) =
+                Self::duplicity_backup(config, server_folder, extra_folder_url, args, full).await
+            {
+                return Err(format!(
+                    "[BACKUP] Failed to perform duplicity backup of extra paths:\n{}",
+                    x
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    // duplicity matches exclusions against full paths, so they are made
+    // relative to the folder actually backed up, which may be a snapshot
+    fn world_args(config: &BackupConfig, backup_source: &str) -> Vec<String> {
+        let mut args = Vec::new();
+        for pattern in &config.backup_excludes {
+            args.push("--exclude".into());
+            args.push(format!(
+                "{}/{}",
+                backup_source.trim_end_matches('/'),
+                pattern
+            ));
+        }
+        args.extend(config.duplicity_extra_args.iter().cloned());
+        args
+    }
+
+    // Sync failures are counted in `status` when given
+    async fn cleanup_and_sync(
+        config: &BackupConfig,
+        targets: &Targets,
+        status: Option<&SharedStatus>,
+    ) -> Result<(), String> {
+        if let Err(x) = Duplicity::cleanup_old(config, &targets.backup_folder_url).await {
+            return Err(format!(
+                "[BACKUP] Failed to perform duplicity cleanup:\n{}",
+                x
+            ));
+        }
+
+        if let Some((_, extra_folder_url, _)) = &targets.extra {
+            if let Err(x) = Duplicity::cleanup_old(config, extra_folder_url).await {
+                return Err(format!(
+                    "[BACKUP] Failed to perform duplicity cleanup of extra paths:\n{}",
+                    x
+                ));
+            }
+        }
+
+        if let (Some(remote), Some(local_folder)) = (&config.rclone_path, &targets.local_folder) {
+            // An inconsistent local backup would replace the good copy on
+            // the remote
+            let mut folders = vec![targets.backup_folder_url.as_str()];
+            if let Some((_, extra_folder_url, _)) = &targets.extra {
+                folders.push(extra_folder_url);
+            }
+
+            for folder in folders {
+                if let Err(x) = Duplicity::check_collection(config, folder).await {
+                    return Err(format!(
+                        "[BACKUP] Local backup data was not synced to remote:\n{}",
                         x
-                    )];
+                    ));
                 }
+            }
 
-                if let Some(remote) = &config.rclone_path {
-                    let mut sync_attempts = 0u32;
+            // Computed once so that retries go to the same folder
+            let remote = match &config.remote_path_template {
+                Some(template) => Rclone::dated_path(remote, template, Local::now()),
+                None => remote.clone(),
+            };
 
-                    let mut err = None;
-                    while sync_attempts < 5 {
-                        if let Err(new_err) = Rclone::sync(remote, &backup_folder).await {
-                            sync_attempts += 1;
-                            err = Some(new_err);
-                        } else {
-                            break;
-                        }
+            let mut sync_attempts = 0u32;
+
+            let mut err = None;
+            while sync_attempts < 5 {
+                let res = match Rclone::sync(config, &remote, local_folder).await {
+                    Ok(()) if config.verify_remote_sync => {
+                        Rclone::check(config, &remote, local_folder).await
                     }
+                    res => res,
+                };
 
-                    if let Some(err) = err {
-                        if sync_attempts >= 5 {
-                            return vec![format!("[ServerManager] [BACKUP] Failed to sync backup data to remote:\n{}", err)];
-                        } else {
-                            println!("[ServerManager] [BACKUP] At least one recoverable error occured while trying to sync backup data to remote:\n{}", err);
-                        }
+                if let Err(new_err) = res {
+                    sync_attempts += 1;
+                    err = Some(new_err);
+                } else {
+                    break;
+                }
+            }
+
+            if let Some(err) = err {
+                if let Some(Ok(mut status)) = status.map(|status| status.lock()) {
+                    if sync_attempts >= 5 {
+                        status.sync_failures_fatal += 1;
+                    } else {
+                        status.sync_failures_recoverable += 1;
                     }
+                }
 
-                    println!("[ServerManager] [BACKUP] Remote backup sync complete.")
+                if sync_attempts >= 5 {
+                    return Err(format!(
+                        "[BACKUP] Failed to sync backup data to remote:\n{}",
+                        err
+                    ));
+                } else {
+                    warn!("[BACKUP] At least one recoverable error occured while trying to sync backup data to remote:\n{}", err);
                 }
             }
-        } else {
-            pending::<()>().await;
-            unreachable!()
+
+            info!("[BACKUP] Remote backup sync complete.")
         }
+
+        Ok(())
     }
 }
@@ -1,13 +1,51 @@
-use std::{path::Path, process::Stdio};
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    process::Stdio,
+    sync::atomic::{AtomicI32, Ordering},
+    time::{Duration, UNIX_EPOCH},
+};
 
 use anyhow::{anyhow, Result};
-use async_std::{io::ReadExt, process::Command};
+use async_std::{
+    fs::{self, File},
+    io::{prelude::BufReadExt, BufReader, ReadExt, WriteExt},
+    os::unix::process::CommandExt,
+    prelude::FutureExt as AsyncStdFutureExt,
+    process::{Child, Command},
+};
 use async_walkdir::WalkDir;
+use chrono::{DateTime, Local};
 use futures::StreamExt;
+use nix::{
+    libc,
+    sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal},
+};
+use url::Url;
+
+use crate::{
+    config::{BackupConfig, BackupPriority, DuplicityEncryption, SnapshotMode},
+    webhook::fill_template,
+};
+
+const WRITE_MARKER_NAME: &str = ".server-manager-write-check";
+
+// Process group of the running backup tool, which no longer receives terminal
+// signals as it runs in its own session
+static BACKUP_PGID: AtomicI32 = AtomicI32::new(0);
 
 pub struct Rclone;
 
 impl Rclone {
+    // Set by `sync_argv`, from the config or when backups are verbose
+    pub const MANAGED_FLAGS: [&'static str; 5] = [
+        "--stats",
+        "--stats-one-line",
+        "--stats-log-level",
+        "--transfers",
+        "--bwlimit",
+    ];
+
     pub async fn is_available() -> Result<bool> {
         let mut child = Command::new("rclone")
             .arg("--help")
@@ -46,43 +84,359 @@ impl Rclone {
         }
     }
 
-    pub async fn sync(remote: &str, local: &str) -> Result<()> {
+    // Catches read-only remotes, which `check_path` cannot tell apart
+    pub async fn check_write(path: &str) -> Result<()> {
+        let marker = Self::write_marker_path(path);
+
+        let output = Command::new("rclone")
+            .arg("touch")
+            .arg(&marker)
+            .output()
+            .await?;
+
+        if !output.status.success() {
+            return Err(anyhow!(
+                "rclone failed to write to remote path:\n{}",
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+
+        let output = Command::new("rclone")
+            .arg("deletefile")
+            .arg(&marker)
+            .output()
+            .await?;
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "rclone failed to delete from remote path:\n{}",
+                String::from_utf8_lossy(&output.stderr)
+            ))
+        }
+    }
+
+    // Remote roots such as `b2:` must not get a separator
+    pub fn write_marker_path(path: &str) -> String {
+        join_remote_path(path, WRITE_MARKER_NAME)
+    }
+
+    // Appends `template` to `path`, with the date placeholders filled in
+    pub fn dated_path(path: &str, template: &str, time: DateTime<Local>) -> String {
+        let subpath = fill_template(
+            template,
+            &[
+                ("{year}", time.format("%Y").to_string()),
+                ("{month}", time.format("%m").to_string()),
+                ("{day}", time.format("%d").to_string()),
+                ("{hour}", time.format("%H").to_string()),
+            ],
+        );
+        join_remote_path(path, &subpath)
+    }
+
+    pub async fn sync(config: &BackupConfig, remote: &str, local: &str) -> Result<()> {
+        let argv = niced(
+            Self::sync_argv(
+                remote,
+                local,
+                config.verbose_backup,
+                config.rclone_transfers,
+                config.rclone_bwlimit.as_deref(),
+                &config.rclone_extra_args,
+            ),
+            config.priority,
+        );
+        match run_backup_command(
+            &argv,
+            &[],
+            config.verbose_backup,
+            config.dry_run,
+            config.command_timeout,
+        )
+        .await?
+        {
+            None => Ok(()),
+            Some(err) => Err(anyhow!("rclone failed to sync to remote:\n{}", err)),
+        }
+    }
+
+    // Fails with the list of differences if the remote does not match the
+    // local folder
+    pub async fn check(config: &BackupConfig, remote: &str, local: &str) -> Result<()> {
+        let argv = niced(Self::check_argv(remote, local), config.priority);
+        match run_backup_command(&argv, &[], false, config.dry_run, config.command_timeout).await? {
+            None => Ok(()),
+            Some(err) => Err(anyhow!("remote does not match backup data:\n{}", err)),
+        }
+    }
+
+    pub fn check_argv(remote: &str, local: &str) -> Vec<String> {
+        // Files only present on the remote are left to the next sync
+        vec![
+            "rclone".into(),
+            "check".into(),
+            local.into(),
+            remote.into(),
+            "--one-way".into(),
+        ]
+    }
+
+    pub fn sync_argv(
+        remote: &str,
+        local: &str,
+        verbose: bool,
+        transfers: Option<u32>,
+        bwlimit: Option<&str>,
+        extra_args: &[String],
+    ) -> Vec<String> {
         // rclone sync local remote
-        let mut child = Command::new("nice")
-            .arg("-n")
-            .arg("10")
-            .arg("ionice")
-            .arg("-c")
-            .arg("3")
-            .arg("rclone")
-            .arg("sync")
-            .arg(local)
-            .arg(remote)
+        let mut args = vec!["rclone".into(), "sync".into(), local.into(), remote.into()];
+
+        if verbose {
+            args.extend(
+                [
+                    "--stats",
+                    "30s",
+                    "--stats-one-line",
+                    "--stats-log-level",
+                    "NOTICE",
+                ]
+                .map(String::from),
+            );
+        }
+
+        if let Some(transfers) = transfers {
+            args.push("--transfers".into());
+            args.push(transfers.to_string());
+        }
+
+        if let Some(bwlimit) = bwlimit {
+            args.push("--bwlimit".into());
+            args.push(bwlimit.into());
+        }
+
+        args.extend(extra_args.iter().cloned());
+        args
+    }
+}
+
+// Remote roots such as `remote:` must not be followed by a separator
+fn join_remote_path(path: &str, name: &str) -> String {
+    if path.ends_with(':') || path.ends_with('/') {
+        format!("{}{}", path, name)
+    } else {
+        format!("{}/{}", path, name)
+    }
+}
+
+pub struct Sendmail;
+
+impl Sendmail {
+    // sendmail has no portable way to check it works without sending a mail,
+    // so this only looks for it in the same places it is spawned from
+    pub fn is_available() -> bool {
+        std::env::var_os("PATH")
+            .map(|path| std::env::split_paths(&path).any(|dir| dir.join("sendmail").is_file()))
+            .unwrap_or(false)
+    }
+}
+
+pub struct Nice;
+
+impl Nice {
+    // Also fails if the niceness cannot be applied
+    pub async fn is_available(nice: i32) -> bool {
+        Command::new("nice")
+            .args(["-n", &nice.to_string(), "true"])
             .stderr(Stdio::piped())
+            .stdout(Stdio::piped())
+            .status()
+            .await
+            .is_ok_and(|status| status.success())
+    }
+}
+
+pub struct Ionice;
+
+impl Ionice {
+    // Also fails if the scheduling class cannot be applied
+    pub async fn is_available(class: u8) -> bool {
+        Command::new("ionice")
+            .args(["-c", &class.to_string(), "true"])
+            .stderr(Stdio::piped())
+            .stdout(Stdio::piped())
+            .status()
+            .await
+            .is_ok_and(|status| status.success())
+    }
+}
+
+pub struct Curl;
+
+impl Curl {
+    pub async fn is_available() -> Result<bool> {
+        let mut child = Command::new("curl")
+            .arg("--version")
+            .stderr(Stdio::piped())
+            .stdout(Stdio::piped())
             .spawn()?;
 
-        if child.status().await?.success() {
+        Ok(child.status().await?.success())
+    }
+
+    pub async fn request(
+        method: &str,
+        url: &str,
+        headers: &[(String, String)],
+        body: &str,
+    ) -> Result<()> {
+        // Options are passed as a config file through stdin so that secrets
+        // such as authorization headers do not show up in the process list
+        let mut options = format!(
+            "request = {}\nurl = {}\ndata-binary = {}\n",
+            curl_quote(method),
+            curl_quote(url),
+            curl_quote(body)
+        );
+        for (name, value) in headers {
+            options += &format!("header = {}\n", curl_quote(&format!("{}: {}", name, value)));
+        }
+
+        let mut child = Command::new("curl")
+            .arg("--silent")
+            .arg("--show-error")
+            .arg("--fail")
+            .arg("--max-time")
+            .arg("30")
+            .arg("--config")
+            .arg("-")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(options.as_bytes()).await?;
+        }
+
+        let output = child.output().await?;
+        if output.status.success() {
             Ok(())
         } else {
-            let err = if let Some(mut stderr) = child.stderr {
-                let mut out = String::new();
-                if stderr.read_to_string(&mut out).await.is_ok() {
-                    out
-                } else {
-                    "failed to obtain error message (stderr failed)".into()
-                }
-            } else {
-                "failed to obtain error message (no stderr)".into()
-            };
+            Err(anyhow!(
+                "curl failed to send request:\n{}",
+                String::from_utf8_lossy(&output.stderr)
+            ))
+        }
+    }
+}
+
+fn curl_quote(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
 
-            Err(anyhow!("rclone failed to sync to remote:\n{}", err))
+pub struct Dmesg;
+
+impl Dmesg {
+    // Returns None if the kernel log cannot be read, which usually requires
+    // privileges.
+    pub async fn was_oom_killed(pid: u32) -> Option<bool> {
+        let output = Command::new("dmesg").output().await.ok()?;
+        if !output.status.success() {
+            return None;
+        }
+
+        let killed = format!("Killed process {} ", pid);
+        Some(
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .any(|line| line.contains(&killed)),
+        )
+    }
+}
+
+pub struct Gzip;
+
+impl Gzip {
+    pub async fn compress(data: &[u8]) -> Result<Vec<u8>> {
+        let mut child = Command::new("gzip")
+            .arg("-c")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // Written while the output is read, as gzip blocks once its output pipe is full
+        let stdin = child.stdin.take();
+        let write = async move {
+            if let Some(mut stdin) = stdin {
+                stdin.write_all(data).await?;
+            }
+            Ok::<_, std::io::Error>(())
+        };
+
+        let (written, output) = futures::join!(write, child.output());
+        let output = output?;
+        written?;
+
+        if output.status.success() {
+            Ok(output.stdout)
+        } else {
+            Err(anyhow!(
+                "gzip failed to compress data:\n{}",
+                String::from_utf8_lossy(&output.stderr)
+            ))
         }
     }
 }
 
+pub struct Gpg;
+
+impl Gpg {
+    pub async fn has_key(key: &str) -> Result<bool> {
+        let mut child = Command::new("gpg")
+            .arg("--list-keys")
+            .arg("--")
+            .arg(key)
+            .stderr(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        Ok(child.status().await?.success())
+    }
+}
+
 pub struct Duplicity;
 
 impl Duplicity {
+    pub const MANAGED_FLAGS: [&'static str; 10] = [
+        "--no-encryption",
+        "--encrypt-key",
+        "--use-agent",
+        "--allow-source-mismatch",
+        "--full-if-older-than",
+        "--force",
+        "--include",
+        "--exclude",
+        "--progress",
+        "--no-print-statistics",
+    ];
+
     pub async fn is_available() -> Result<bool> {
         let mut child = Command::new("duplicity")
             .arg("--help")
@@ -94,30 +448,421 @@ impl Duplicity {
     }
 
     pub async fn backup(
-        full_if_older_than_hours: u32,
+        config: &BackupConfig,
         to_backup: &str,
         backup_to: &str,
+        extra_args: &[String],
     ) -> Result<()> {
-        let mut child = Command::new("nice")
-            .arg("-n")
-            .arg("10")
-            .arg("ionice")
-            .arg("-c")
-            .arg("3")
-            .arg("duplicity")
-            .arg("--no-encryption")
-            .arg("--allow-source-mismatch")
-            .arg("--full-if-older-than")
-            .arg(format!("{}h", full_if_older_than_hours))
-            .arg(to_backup)
-            .arg(backup_to)
-            .stderr(Stdio::piped())
-            .spawn()?;
+        Self::run_backup(config, to_backup, backup_to, extra_args, false).await
+    }
 
-        if child.status().await?.success() {
-            Ok(())
+    // Starts a new backup chain, whatever the age of the last full backup
+    pub async fn full_backup(
+        config: &BackupConfig,
+        to_backup: &str,
+        backup_to: &str,
+        extra_args: &[String],
+    ) -> Result<()> {
+        Self::run_backup(config, to_backup, backup_to, extra_args, true).await
+    }
+
+    async fn run_backup(
+        config: &BackupConfig,
+        to_backup: &str,
+        backup_to: &str,
+        extra_args: &[String],
+        full: bool,
+    ) -> Result<()> {
+        let encryption = config.duplicity_encryption.as_ref();
+        let argv = niced(
+            Self::backup_argv(
+                (!full).then_some(config.full_backup_every),
+                to_backup,
+                backup_to,
+                config.duplicity_volsize_mb,
+                extra_args,
+                encryption,
+                config.verbose_backup,
+            ),
+            config.priority,
+        );
+
+        match run_backup_command(
+            &argv,
+            &Self::encryption_env(encryption),
+            config.verbose_backup,
+            config.dry_run,
+            config.command_timeout,
+        )
+        .await?
+        {
+            None => Ok(()),
+            Some(err) => Err(anyhow!("duplicity failed to make backup:\n{}", err)),
+        }
+    }
+
+    // Always makes a full backup if `full_if_older_than_hours` is None
+    pub fn backup_argv(
+        full_if_older_than_hours: Option<u32>,
+        to_backup: &str,
+        backup_to: &str,
+        volsize_mb: Option<u32>,
+        extra_args: &[String],
+        encryption: Option<&DuplicityEncryption>,
+        verbose: bool,
+    ) -> Vec<String> {
+        let mut args = vec!["duplicity".into()];
+        if full_if_older_than_hours.is_none() {
+            args.push("full".into());
+        }
+
+        if encryption.is_none() {
+            args.push("--no-encryption".into());
+        }
+        args.extend(Self::encryption_args(encryption));
+
+        args.push("--allow-source-mismatch".into());
+        if let Some(hours) = full_if_older_than_hours {
+            args.push("--full-if-older-than".into());
+            args.push(format!("{}h", hours));
+        }
+
+        if let Some(volsize) = volsize_mb {
+            args.push("--volsize".into());
+            args.push(volsize.to_string());
+        }
+
+        // Statistics are only useful in the progress log, they would otherwise
+        // be printed to the terminal after every backup
+        if verbose {
+            args.push("--progress".into());
         } else {
-            let err = if let Some(mut stderr) = child.stderr {
+            args.push("--no-print-statistics".into());
+        }
+
+        args.extend(extra_args.iter().cloned());
+        args.push(to_backup.into());
+        args.push(backup_to.into());
+
+        args
+    }
+
+    pub async fn cleanup_old(config: &BackupConfig, backup_to: &str) -> Result<()> {
+        let encryption = config.duplicity_encryption.as_ref();
+        let argv = niced(
+            Self::cleanup_argv(config.keep_full_backup, backup_to, encryption),
+            config.priority,
+        );
+
+        match run_backup_command(
+            &argv,
+            &Self::encryption_env(encryption),
+            config.verbose_backup,
+            config.dry_run,
+            config.command_timeout,
+        )
+        .await?
+        {
+            None => Ok(()),
+            Some(err) => Err(anyhow!(
+                "duplicity failed to clean up old backups:\n{}",
+                err
+            )),
+        }
+    }
+
+    // Extra arguments are only meant for backups, other commands may reject them
+    pub fn cleanup_argv(
+        keep_full: u32,
+        backup_to: &str,
+        encryption: Option<&DuplicityEncryption>,
+    ) -> Vec<String> {
+        let mut args = vec!["duplicity".into()];
+        args.extend(Self::encryption_args(encryption));
+
+        args.push("--allow-source-mismatch".into());
+        args.push("remove-all-but-n-full".into());
+        args.push(keep_full.to_string());
+        args.push("--force".into());
+        args.push(backup_to.into());
+
+        args
+    }
+
+    // duplicity only warns about incomplete or orphaned backup sets, so they
+    // are found in its output
+    pub async fn check_collection(config: &BackupConfig, backup_to: &str) -> Result<()> {
+        let encryption = config.duplicity_encryption.as_ref();
+        let argv = niced(
+            Self::collection_status_argv(backup_to, encryption),
+            config.priority,
+        );
+        let env = Self::encryption_env(encryption);
+
+        if config.dry_run {
+            run_backup_command(&argv, &env, false, true, None).await?;
+            return Ok(());
+        }
+
+        let mut cmd = backup_command(&argv, &env);
+        cmd.kill_on_drop(true)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let child = cmd.spawn()?;
+        let _group = BackupGroup::new(&child);
+
+        // The child is killed when the timed out future is dropped
+        let output = match config.command_timeout {
+            Some(timeout) => child.output().timeout(timeout).await.map_err(|_| {
+                anyhow!(
+                    "duplicity timed out after {} minutes while reading backup collection",
+                    timeout.as_secs() / 60
+                )
+            })??,
+            None => child.output().await?,
+        };
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if !output.status.success() {
+            return Err(anyhow!(
+                "duplicity failed to read backup collection:\n{}",
+                stderr
+            ));
+        }
+
+        let warnings = stdout
+            .lines()
+            .chain(stderr.lines())
+            .filter(|line| line.starts_with("Warning, found"))
+            .collect::<Vec<_>>();
+        if !warnings.is_empty() {
+            return Err(anyhow!(
+                "backup collection is inconsistent:\n{}",
+                warnings.join("\n")
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn collection_status_argv(
+        backup_to: &str,
+        encryption: Option<&DuplicityEncryption>,
+    ) -> Vec<String> {
+        let mut args = vec!["duplicity".into(), "collection-status".into()];
+        args.extend(Self::encryption_args(encryption));
+        args.push(backup_to.into());
+        args
+    }
+
+    fn encryption_args(encryption: Option<&DuplicityEncryption>) -> Vec<String> {
+        match encryption {
+            Some(DuplicityEncryption::GpgAgent(key)) => {
+                vec!["--encrypt-key".into(), key.clone(), "--use-agent".into()]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn encryption_env(encryption: Option<&DuplicityEncryption>) -> Vec<(&'static str, String)> {
+        match encryption {
+            Some(DuplicityEncryption::Passphrase(passphrase)) => {
+                vec![("PASSPHRASE", passphrase.clone())]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+// Backup tools run with a low priority by default to avoid slowing down the server
+fn niced(args: Vec<String>, priority: BackupPriority) -> Vec<String> {
+    let mut niced = Vec::new();
+    if let Some(nice) = priority.nice {
+        niced.extend(["nice".into(), "-n".into(), nice.to_string()]);
+    }
+    if let Some(class) = priority.ionice_class {
+        niced.extend(["ionice".into(), "-c".into(), class.to_string()]);
+    }
+    niced.extend(args);
+    niced
+}
+
+// In dry-run mode, only logs the command that would have been run.
+async fn run_backup_command(
+    argv: &[String],
+    env: &[(&str, String)],
+    verbose: bool,
+    dry_run: bool,
+    timeout: Option<Duration>,
+) -> Result<Option<String>> {
+    if dry_run {
+        // Values of environment variables are secrets, such as passphrases
+        let env_names = env.iter().map(|(name, _)| format!("{}=*** ", name));
+        info!(
+            "[BACKUP] Dry run: {}{}",
+            env_names.collect::<String>(),
+            argv.iter()
+                .map(|arg| shell_quote(&redact_credentials(arg)))
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+        return Ok(None);
+    }
+
+    wait_for_child(backup_command(argv, env), verbose, timeout).await
+}
+
+// Forwards SIGINT and SIGTERM to the running backup tool, for backups made
+// without a manager, whose signals would otherwise leave it running. The tool
+// then fails, letting the backup stop as after any failure.
+pub fn forward_signals_to_backups() -> Result<()> {
+    let handler = SigAction::new(
+        SigHandler::Handler(forward_to_backup),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    unsafe {
+        signal::sigaction(Signal::SIGINT, &handler)?;
+        signal::sigaction(Signal::SIGTERM, &handler)?;
+    }
+    Ok(())
+}
+
+extern "C" fn forward_to_backup(sig: libc::c_int) {
+    let pgid = BACKUP_PGID.load(Ordering::SeqCst);
+    if pgid > 0 {
+        unsafe { libc::kill(-pgid, sig) };
+    } else {
+        unsafe { libc::_exit(128 + sig) };
+    }
+}
+
+// Records the process group of a backup tool while it runs
+struct BackupGroup;
+
+impl BackupGroup {
+    fn new(child: &Child) -> Self {
+        BACKUP_PGID.store(child.id() as i32, Ordering::SeqCst);
+        Self
+    }
+}
+
+impl Drop for BackupGroup {
+    fn drop(&mut self) {
+        BACKUP_PGID.store(0, Ordering::SeqCst);
+    }
+}
+
+// Backup tools run without a terminal or input, so that a prompt fails the
+// command instead of waiting for an answer that never comes
+fn backup_command(argv: &[String], env: &[(&str, String)]) -> Command {
+    let mut cmd = Command::new(&argv[0]);
+    cmd.args(&argv[1..]).stdin(Stdio::null());
+    for (name, value) in env {
+        cmd.env(name, value);
+    }
+
+    unsafe {
+        cmd.pre_exec(|| nix::unistd::setsid().map(drop).map_err(Into::into));
+    }
+
+    cmd
+}
+
+// URLs can carry credentials, as in b2://account_id:application_key@bucket
+fn redact_credentials(arg: &str) -> String {
+    match Url::parse(arg) {
+        Ok(mut url) if url.password().is_some() => {
+            url.set_password(Some("***")).ok();
+            url.to_string()
+        }
+        _ => arg.into(),
+    }
+}
+
+fn shell_quote(arg: &str) -> String {
+    let is_plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_=./:@%+,".contains(c));
+    if is_plain {
+        arg.into()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+// Runs the command to completion and returns its error output if it failed,
+// killing it if it runs for longer than `timeout`.
+// In verbose mode, its output is also streamed to the log line by line.
+async fn wait_for_child(
+    mut cmd: Command,
+    verbose: bool,
+    timeout: Option<Duration>,
+) -> Result<Option<String>> {
+    // The backup task is dropped when the server exits, which must not leave
+    // a backup running in the background
+    cmd.kill_on_drop(true);
+
+    let mut child = if verbose {
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?
+    } else {
+        cmd.stderr(Stdio::piped()).spawn()?
+    };
+    let _group = BackupGroup::new(&child);
+
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return wait_for_output(&mut child, verbose).await,
+    };
+
+    match wait_for_output(&mut child, verbose).timeout(timeout).await {
+        Ok(res) => res,
+        Err(_) => {
+            child.kill().ok();
+            child.status().await.ok();
+
+            let minutes = timeout.as_secs() / 60;
+            Ok(Some(match pending_prompt(&mut child).await {
+                Some(prompt) => format!(
+                    "the process appears to be waiting for input and was killed after {} minutes, it last printed:\n{}",
+                    minutes, prompt
+                ),
+                None => format!(
+                    "timed out after {} minutes, the process was killed",
+                    minutes
+                ),
+            }))
+        }
+    }
+}
+
+// Unlike log messages, prompts are not followed by a new line. The error
+// output is only left to read when it is not logged as progress.
+async fn pending_prompt(child: &mut Child) -> Option<String> {
+    let mut stderr = child.stderr.take()?;
+    let mut out = String::new();
+    stderr
+        .read_to_string(&mut out)
+        .timeout(Duration::from_secs(5))
+        .await
+        .ok()?
+        .ok()?;
+
+    if out.ends_with('\n') {
+        return None;
+    }
+
+    let prompt = out.rsplit('\n').next()?.trim();
+    (!prompt.is_empty()).then(|| prompt.to_string())
+}
+
+async fn wait_for_output(child: &mut Child, verbose: bool) -> Result<Option<String>> {
+    if !verbose {
+        return if child.status().await?.success() {
+            Ok(None)
+        } else {
+            let err = if let Some(mut stderr) = child.stderr.take() {
                 let mut out = String::new();
                 if stderr.read_to_string(&mut out).await.is_ok() {
                     out
@@ -128,46 +873,237 @@ impl Duplicity {
                 "failed to obtain error message (no stderr)".into()
             };
 
-            Err(anyhow!("duplicity failed to make backup:\n{}", err))
+            Ok(Some(err))
+        };
+    }
+
+    // Both pipes are drained at the same time so neither of them can fill up
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+
+    let log_stdout = async {
+        if let Some(stdout) = stdout {
+            let mut lines = BufReader::new(stdout).lines();
+            while let Some(Ok(line)) = lines.next().await {
+                log_progress(&line);
+            }
+        }
+    };
+
+    let log_stderr = async {
+        let mut captured = String::new();
+        if let Some(stderr) = stderr {
+            let mut lines = BufReader::new(stderr).lines();
+            while let Some(Ok(line)) = lines.next().await {
+                log_progress(&line);
+                captured.push_str(&line);
+                captured.push('\n');
+            }
         }
+        captured
+    };
+
+    let (_, err) = futures::join!(log_stdout, log_stderr);
+
+    if child.status().await?.success() {
+        Ok(None)
+    } else {
+        Ok(Some(err))
     }
+}
 
-    pub async fn cleanup_old(keep_full: u32, backup_to: &str) -> Result<()> {
-        let mut child = Command::new("nice")
-            .arg("-n")
-            .arg("10")
-            .arg("ionice")
-            .arg("-c")
-            .arg("3")
-            .arg("duplicity")
-            .arg("--allow-source-mismatch")
-            .arg("remove-all-but-n-full")
-            .arg(keep_full.to_string())
-            .arg("--force")
-            .arg(backup_to)
+fn log_progress(line: &str) {
+    // Progress bars redraw themselves with carriage returns
+    for part in line
+        .split('\r')
+        .map(str::trim_end)
+        .filter(|p| !p.is_empty())
+    {
+        info!("[BACKUP] {}", part);
+    }
+}
+
+pub struct Snapshot;
+
+const SNAPSHOT_NAME: &str = "server-manager-backup";
+
+impl Snapshot {
+    pub async fn is_available(mode: SnapshotMode) -> Result<bool> {
+        let mut cmd = match mode {
+            SnapshotMode::Btrfs => Command::new("btrfs"),
+            SnapshotMode::Zfs => Command::new("zfs"),
+        };
+
+        let mut child = cmd
+            .arg("version")
             .stderr(Stdio::piped())
+            .stdout(Stdio::piped())
             .spawn()?;
 
-        if child.status().await?.success() {
-            Ok(())
-        } else {
-            let err = if let Some(mut stderr) = child.stderr {
-                let mut out = String::new();
-                if stderr.read_to_string(&mut out).await.is_ok() {
-                    out
+        Ok(child.status().await?.success())
+    }
+
+    // Checked with the config, as a world that cannot be snapshotted would
+    // otherwise only fail once a backup runs
+    pub async fn check_world(mode: SnapshotMode, world_folder: &str) -> Result<()> {
+        match mode {
+            SnapshotMode::Btrfs => {
+                let output = Command::new("btrfs")
+                    .arg("subvolume")
+                    .arg("show")
+                    .arg(world_folder)
+                    .output()
+                    .await?;
+
+                if !output.status.success() {
+                    return Err(anyhow!(
+                        "world folder {} is not a btrfs subvolume:\n{}",
+                        world_folder,
+                        String::from_utf8_lossy(&output.stderr)
+                    ));
+                }
+            }
+            SnapshotMode::Zfs => {
+                Self::zfs_dataset(world_folder).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Takes a read-only snapshot of the filesystem holding `world_folder` and
+    // returns the path at which the world can be read from the snapshot.
+    pub async fn create(mode: SnapshotMode, world_folder: &str) -> Result<String> {
+        // A snapshot left over by an interrupted backup would prevent creating a new one
+        Self::delete(mode, world_folder).await.ok();
+
+        match mode {
+            SnapshotMode::Btrfs => {
+                let snapshot = Self::btrfs_snapshot_path(world_folder)?;
+                let output = Command::new("btrfs")
+                    .arg("subvolume")
+                    .arg("snapshot")
+                    .arg("-r")
+                    .arg(world_folder)
+                    .arg(&snapshot)
+                    .output()
+                    .await?;
+
+                if output.status.success() {
+                    Ok(snapshot)
                 } else {
-                    "failed to obtain error message (stderr failed)".into()
+                    Err(anyhow!(
+                        "btrfs failed to create snapshot:\n{}",
+                        String::from_utf8_lossy(&output.stderr)
+                    ))
                 }
-            } else {
-                "failed to obtain error message (no stderr)".into()
-            };
+            }
+            SnapshotMode::Zfs => {
+                let (dataset, mountpoint) = Self::zfs_dataset(world_folder).await?;
+                let output = Command::new("zfs")
+                    .arg("snapshot")
+                    .arg(format!("{}@{}", dataset, SNAPSHOT_NAME))
+                    .output()
+                    .await?;
+
+                if !output.status.success() {
+                    return Err(anyhow!(
+                        "zfs failed to create snapshot:\n{}",
+                        String::from_utf8_lossy(&output.stderr)
+                    ));
+                }
+
+                let relative = Path::new(world_folder)
+                    .strip_prefix(&mountpoint)
+                    .map_err(|_| {
+                        anyhow!("world folder is not under zfs mountpoint {}", mountpoint)
+                    })?;
+
+                Path::new(&mountpoint)
+                    .join(".zfs")
+                    .join("snapshot")
+                    .join(SNAPSHOT_NAME)
+                    .join(relative)
+                    .into_os_string()
+                    .into_string()
+                    .map_err(|_| anyhow!("failed to convert snapshot path to string"))
+            }
+        }
+    }
+
+    pub async fn delete(mode: SnapshotMode, world_folder: &str) -> Result<()> {
+        let output = match mode {
+            SnapshotMode::Btrfs => {
+                Command::new("btrfs")
+                    .arg("subvolume")
+                    .arg("delete")
+                    .arg(Self::btrfs_snapshot_path(world_folder)?)
+                    .output()
+                    .await?
+            }
+            SnapshotMode::Zfs => {
+                let (dataset, _) = Self::zfs_dataset(world_folder).await?;
+                Command::new("zfs")
+                    .arg("destroy")
+                    .arg(format!("{}@{}", dataset, SNAPSHOT_NAME))
+                    .output()
+                    .await?
+            }
+        };
 
+        if output.status.success() {
+            Ok(())
+        } else {
             Err(anyhow!(
-                "duplicity failed to clean up old backups:\n{}",
-                err
+                "failed to delete snapshot:\n{}",
+                String::from_utf8_lossy(&output.stderr)
             ))
         }
     }
+
+    fn btrfs_snapshot_path(world_folder: &str) -> Result<String> {
+        let world = Path::new(world_folder);
+        let name = world
+            .file_name()
+            .ok_or_else(|| anyhow!("world folder has no name"))?
+            .to_string_lossy();
+
+        world
+            .with_file_name(format!(".{}.{}", name, SNAPSHOT_NAME))
+            .into_os_string()
+            .into_string()
+            .map_err(|_| anyhow!("failed to convert snapshot path to string"))
+    }
+
+    async fn zfs_dataset(world_folder: &str) -> Result<(String, String)> {
+        let output = Command::new("zfs")
+            .arg("list")
+            .arg("-H")
+            .arg("-o")
+            .arg("name,mountpoint")
+            .arg(world_folder)
+            .output()
+            .await?;
+
+        if !output.status.success() {
+            return Err(anyhow!(
+                "zfs failed to find the dataset of the world folder:\n{}",
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        match stdout.trim_end().split_once('\t') {
+            Some((dataset, mountpoint)) => Ok((dataset.into(), mountpoint.into())),
+            None => Err(anyhow!("unexpected zfs list output:\n{}", stdout)),
+        }
+    }
+}
+
+// Space available to unprivileged users, excluding blocks reserved for root
+pub fn get_free_space(path: impl AsRef<Path>) -> Result<u64> {
+    let stat = nix::sys::statvfs::statvfs(path.as_ref())?;
+    Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
 }
 
 pub async fn get_folder_size(path: impl AsRef<Path>) -> Result<u64> {
@@ -186,3 +1122,364 @@ pub async fn get_folder_size(path: impl AsRef<Path>) -> Result<u64> {
     }
     Ok(res)
 }
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct RegionChecksum {
+    pub checksum: u64,
+    // Nanoseconds since the epoch
+    pub modified: u128,
+    pub len: u64,
+}
+
+// Region files of a world by path relative to the world folder
+pub type RegionChecksums = BTreeMap<String, RegionChecksum>;
+
+// Modification time in nanoseconds since the epoch and size of region files,
+// by path relative to the world folder
+pub type RegionFiles = BTreeMap<String, (u128, u64)>;
+
+// Only reads metadata, so it is quick enough to run while saving is disabled
+pub async fn get_region_files(world_folder: impl AsRef<Path>) -> Result<RegionFiles> {
+    let world_folder = world_folder.as_ref();
+    let mut entries = WalkDir::new(world_folder);
+    let mut res = BTreeMap::new();
+    while let Some(entry) = entries.next().await {
+        let entry = entry?;
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "mca") {
+            continue;
+        }
+
+        let metadata = entry.metadata().await?;
+        if !metadata.is_file() {
+            continue;
+        }
+
+        let relative = path
+            .strip_prefix(world_folder)?
+            .to_string_lossy()
+            .into_owned();
+        res.insert(relative, region_file_metadata(&metadata)?);
+    }
+    Ok(res)
+}
+
+// Files that were modified or removed since they were listed in `files` are
+// left out, as the server saved them in the meantime.
+pub async fn get_region_checksums(
+    world_folder: impl AsRef<Path>,
+    files: &RegionFiles,
+) -> Result<RegionChecksums> {
+    let world_folder = world_folder.as_ref();
+    let mut res = BTreeMap::new();
+    for (relative, &(modified, len)) in files {
+        let path = world_folder.join(relative);
+        let checksum = match checksum_file(&path).await {
+            Ok(checksum) => checksum,
+            Err(_) if !path.exists() => continue,
+            Err(err) => return Err(err),
+        };
+
+        let unchanged = match fs::metadata(&path).await {
+            Ok(metadata) => region_file_metadata(&metadata)? == (modified, len),
+            Err(_) => false,
+        };
+        if unchanged {
+            res.insert(
+                relative.clone(),
+                RegionChecksum {
+                    checksum,
+                    modified,
+                    len,
+                },
+            );
+        }
+    }
+    Ok(res)
+}
+
+fn region_file_metadata(metadata: &std::fs::Metadata) -> Result<(u128, u64)> {
+    Ok((
+        metadata.modified()?.duration_since(UNIX_EPOCH)?.as_nanos(),
+        metadata.len(),
+    ))
+}
+
+// FNV-1a, which is stable across versions unlike the hasher of the standard
+// library, and enough to notice corruption
+async fn checksum_file(path: &Path) -> Result<u64> {
+    let mut file = File::open(path).await?;
+    let mut buf = vec![0; 64 * 1024];
+    let mut hash: u64 = 0xcbf29ce484222325;
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+
+        for byte in &buf[..read] {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    Ok(hash)
+}
+
+// One file per line: checksum, modification time, size and path
+pub fn load_region_checksums(path: &Path) -> Result<RegionChecksums> {
+    let content = std::fs::read_to_string(path)?;
+    let mut res = BTreeMap::new();
+    for line in content.lines().filter(|line| !line.is_empty()) {
+        let mut fields = line.splitn(4, ' ');
+        let mut next = || {
+            fields
+                .next()
+                .ok_or_else(|| anyhow!("invalid line in {}: {}", path.display(), line))
+        };
+
+        let checksum = u64::from_str_radix(next()?, 16)?;
+        let modified = next()?.parse()?;
+        let len = next()?.parse()?;
+        let file = next()?;
+        res.insert(
+            file.into(),
+            RegionChecksum {
+                checksum,
+                modified,
+                len,
+            },
+        );
+    }
+    Ok(res)
+}
+
+pub fn save_region_checksums(path: &Path, checksums: &RegionChecksums) -> Result<()> {
+    let mut content = String::new();
+    for (file, entry) in checksums {
+        content += &format!(
+            "{:016x} {} {} {}\n",
+            entry.checksum, entry.modified, entry.len, file
+        );
+    }
+
+    // Written next to the manifest then renamed, so that an interrupted write
+    // does not lose the previous one
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, content)?;
+    std::fs::rename(tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn argv(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn flags(argv: &[String]) -> Vec<&str> {
+        argv.iter()
+            .filter(|arg| arg.starts_with("--"))
+            .map(|arg| arg.split('=').next().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn incremental_backup_argv() {
+        assert_eq!(
+            Duplicity::backup_argv(
+                Some(336),
+                "/world",
+                "file:///backups",
+                None,
+                &[],
+                None,
+                false
+            ),
+            argv(&[
+                "duplicity",
+                "--no-encryption",
+                "--allow-source-mismatch",
+                "--full-if-older-than",
+                "336h",
+                "--no-print-statistics",
+                "/world",
+                "file:///backups",
+            ])
+        );
+    }
+
+    #[test]
+    fn full_backup_argv() {
+        assert_eq!(
+            Duplicity::backup_argv(None, "/world", "file:///backups", Some(50), &[], None, true),
+            argv(&[
+                "duplicity",
+                "full",
+                "--no-encryption",
+                "--allow-source-mismatch",
+                "--volsize",
+                "50",
+                "--progress",
+                "/world",
+                "file:///backups",
+            ])
+        );
+    }
+
+    #[test]
+    fn encrypted_backup_argv() {
+        let agent = DuplicityEncryption::GpgAgent("ABCD".into());
+        let args =
+            Duplicity::backup_argv(Some(1), "/world", "b2://b", None, &[], Some(&agent), false);
+        assert!(!args.contains(&"--no-encryption".into()));
+        assert_eq!(args[1..4], argv(&["--encrypt-key", "ABCD", "--use-agent"]));
+
+        // Passphrases are given through the environment only
+        let passphrase = DuplicityEncryption::Passphrase("secret".into());
+        let args = Duplicity::backup_argv(
+            Some(1),
+            "/world",
+            "b2://b",
+            None,
+            &[],
+            Some(&passphrase),
+            false,
+        );
+        assert!(!args.contains(&"--no-encryption".into()));
+        assert!(!args.iter().any(|arg| arg.contains("secret")));
+        assert_eq!(
+            Duplicity::encryption_env(Some(&passphrase)),
+            [("PASSPHRASE", "secret".to_string())]
+        );
+    }
+
+    #[test]
+    fn extra_args_come_before_positionals() {
+        let extra = argv(&["--asynchronous-upload", "--s3-use-ia"]);
+        let args =
+            Duplicity::backup_argv(Some(1), "/world", "file:///b", None, &extra, None, false);
+        assert_eq!(
+            args[args.len() - 4..],
+            argv(&[
+                "--asynchronous-upload",
+                "--s3-use-ia",
+                "/world",
+                "file:///b"
+            ])
+        );
+    }
+
+    #[test]
+    fn cleanup_and_collection_status_argv() {
+        assert_eq!(
+            Duplicity::cleanup_argv(2, "file:///b", None),
+            argv(&[
+                "duplicity",
+                "--allow-source-mismatch",
+                "remove-all-but-n-full",
+                "2",
+                "--force",
+                "file:///b",
+            ])
+        );
+        assert_eq!(
+            Duplicity::collection_status_argv("file:///b", None),
+            argv(&["duplicity", "collection-status", "file:///b"])
+        );
+    }
+
+    #[test]
+    fn sync_argv() {
+        assert_eq!(
+            Rclone::sync_argv("remote:world", "/backups", false, None, None, &[]),
+            argv(&["rclone", "sync", "/backups", "remote:world"])
+        );
+
+        let extra = argv(&["--fast-list"]);
+        assert_eq!(
+            Rclone::sync_argv(
+                "remote:world",
+                "/backups",
+                true,
+                Some(8),
+                Some("1M"),
+                &extra
+            ),
+            argv(&[
+                "rclone",
+                "sync",
+                "/backups",
+                "remote:world",
+                "--stats",
+                "30s",
+                "--stats-one-line",
+                "--stats-log-level",
+                "NOTICE",
+                "--transfers",
+                "8",
+                "--bwlimit",
+                "1M",
+                "--fast-list",
+            ])
+        );
+    }
+
+    // Extra arguments are rejected if they are in the managed flags, so all
+    // flags set by server-manager must be in them
+    #[test]
+    fn managed_flags_cover_generated_flags() {
+        let agent = DuplicityEncryption::GpgAgent("ABCD".into());
+        let duplicity = [
+            Duplicity::backup_argv(Some(1), "/w", "file:///b", None, &[], None, false),
+            Duplicity::backup_argv(None, "/w", "file:///b", None, &[], Some(&agent), true),
+            Duplicity::cleanup_argv(2, "file:///b", Some(&agent)),
+            Duplicity::collection_status_argv("file:///b", Some(&agent)),
+        ];
+        for args in &duplicity {
+            for flag in flags(args) {
+                assert!(Duplicity::MANAGED_FLAGS.contains(&flag), "{}", flag);
+            }
+        }
+
+        // Only set when configured, which is checked separately for the volume size
+        let volsize =
+            Duplicity::backup_argv(Some(1), "/w", "file:///b", Some(50), &[], None, false);
+        assert!(flags(&volsize).contains(&"--volsize"));
+
+        let rclone = Rclone::sync_argv("remote:", "/b", true, Some(8), Some("1M"), &[]);
+        for flag in flags(&rclone) {
+            assert!(Rclone::MANAGED_FLAGS.contains(&flag), "{}", flag);
+        }
+    }
+
+    #[test]
+    fn niced_wraps_commands() {
+        let args = argv(&["duplicity", "--force"]);
+        let priority = |nice, ionice_class| BackupPriority { nice, ionice_class };
+
+        assert_eq!(niced(args.clone(), priority(None, None)), args);
+        assert_eq!(
+            niced(args.clone(), priority(Some(10), None)),
+            argv(&["nice", "-n", "10", "duplicity", "--force"])
+        );
+        assert_eq!(
+            niced(args.clone(), priority(None, Some(3))),
+            argv(&["ionice", "-c", "3", "duplicity", "--force"])
+        );
+        assert_eq!(
+            niced(args, priority(Some(19), Some(2))),
+            argv(&[
+                "nice",
+                "-n",
+                "19",
+                "ionice",
+                "-c",
+                "2",
+                "duplicity",
+                "--force"
+            ])
+        );
+    }
+}
@@ -1,4 +1,7 @@
 use std::{
+    collections::HashMap,
+    ffi::CString,
+    net::{IpAddr, Ipv6Addr},
     path::{Path, PathBuf},
     time::Duration,
 };
@@ -8,23 +11,133 @@ use lettre::{
     message::{Mailbox, Mailboxes},
     transport::smtp::authentication::Credentials,
 };
+use nix::unistd::{geteuid, getgrouplist, Gid, Group, User};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
+use url::Url;
 
-use crate::cmd_utils::{Duplicity, Rclone};
+use crate::{
+    cmd_utils::{Curl, Duplicity, Gpg, Ionice, Nice, Rclone, Sendmail, Snapshot},
+    log::LogLevel,
+    properties::Properties,
+    server::MAX_RECENT_INCIDENTS,
+};
 
 #[derive(Serialize, Deserialize)]
 pub struct ConfigSerialized {
     name: String,
     auto_restart: bool,
+    #[serde(default = "default_true")]
+    restart_on_clean_exit: bool,
     server_folder: String,
     server_jar: String,
+    // Where the server runs, if not in the server folder
+    #[serde(default)]
+    working_dir: Option<PathBuf>,
+    // The server runs as this user and group, dropping the privileges of the
+    // manager. The group defaults to the primary group of the user.
+    #[serde(default)]
+    run_as_user: Option<String>,
+    #[serde(default)]
+    run_as_group: Option<String>,
     backups: Option<BackupConfigSerialized>,
     java: String,
+    #[serde(default)]
+    java_home: Option<PathBuf>,
     java_args: Vec<String>,
+    #[serde(default)]
+    java_args_file: Option<PathBuf>,
+    #[serde(default)]
+    memory: Option<String>,
+    #[serde(default)]
+    jvm_preset: Option<String>,
+    #[serde(default)]
+    jvm_presets: HashMap<String, Vec<String>>,
     rcon_password: String,
     rcon_port: u16,
+    #[serde(default = "default_rcon_host")]
+    rcon_host: String,
+    // Works around the non-standard responses of Minecraft servers
+    #[serde(default = "default_true")]
+    rcon_minecraft_quirks: bool,
+    #[serde(default)]
+    read_rcon_from_properties: bool,
+    #[serde(default)]
+    ensure_rcon_enabled: bool,
+    #[serde(default)]
+    properties_overrides: Option<HashMap<String, String>>,
+    #[serde(default)]
+    broadcast_style: BroadcastStyle,
+    #[serde(default = "default_broadcast_format")]
+    broadcast_format: Vec<TextComponent>,
+    #[serde(default)]
+    command_profile: CommandProfile,
+    #[serde(default = "default_command_queue_size")]
+    command_queue_size: usize,
+    #[serde(default)]
+    idle_shutdown_mins: Option<u64>,
+    #[serde(default)]
+    restart_after_idle: bool,
+    // Restarts the server once it used more memory than this for a while
+    #[serde(default)]
+    restart_memory_threshold_mb: Option<u64>,
+    // Before planned restarts, waits this long at most for players to leave
+    #[serde(default)]
+    drain_timeout_mins: Option<u64>,
+    // Enables the whitelist while draining, so that no one else joins
+    #[serde(default)]
+    drain_whitelist: bool,
+    #[serde(default)]
+    stable_after_secs: Option<u64>,
+    // Number of recent incidents after which a warning is sent, 0 disables it
+    #[serde(default)]
+    warn_incidents: u32,
+    #[serde(default)]
+    timezone: Option<String>,
+    #[serde(default)]
+    incident_log_lines: usize,
+    // Glob rather than a regular expression, in which only `*` is special
+    #[serde(default)]
+    ready_pattern: Option<String>,
+    #[serde(default)]
+    log_level: LogLevel,
+    // Shown in log messages instead of the name, none if empty
+    #[serde(default)]
+    log_name: Option<String>,
+    // Replaces the name with the MOTD of server.properties, when it has one
+    #[serde(default)]
+    use_motd_as_name: bool,
+    #[serde(default)]
+    on_connect_commands: Vec<String>,
+    #[serde(default)]
+    health_check_interval_secs: Option<u64>,
+    // The `list` command of the command profile if None
+    #[serde(default)]
+    health_check_command: Option<String>,
+    #[serde(default = "default_health_fail_threshold")]
+    health_fail_threshold: u32,
+    #[serde(default)]
+    shutdown_method: ShutdownMethod,
+    #[serde(default)]
+    shutdown_signal: ShutdownSignal,
+    #[serde(default = "default_shutdown_timeout_secs")]
+    shutdown_timeout_secs: u64,
+    #[serde(default = "default_shutdown_announcement")]
+    shutdown_announcement: Option<String>,
+    #[serde(default)]
+    wake_on_connect: bool,
+    #[serde(default = "default_wake_motd")]
+    wake_motd: String,
+    #[serde(default)]
+    restart_on_update: bool,
+    // Exit code of the manager when it stops because of the server failing
+    #[serde(default = "default_failure_exit_code")]
+    failure_exit_code: u8,
     mailing: Option<MailConfigSerialized>,
+    #[serde(default)]
+    webhook: Option<WebhookConfigSerialized>,
+    #[serde(default)]
+    slack: Option<SlackConfigSerialized>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -32,23 +145,484 @@ pub struct BackupConfigSerialized {
     backup_folder: String,
     world_folder: String,
     incremental_freq_hours: u64,
+    #[serde(default)]
+    backup_jitter_secs: u64,
+    #[serde(default)]
+    initial_backup_delay_mins: Option<u64>,
+    #[serde(default = "default_true")]
+    catch_up_backups: bool,
     full_backup_every: u32,
     keep_full_backup: u32,
     rclone_path: Option<String>,
+    #[serde(default = "default_true")]
+    check_rclone_write: bool,
+    #[serde(default)]
+    rclone_transfers: Option<u32>,
+    // Passed to rclone as is, such as `10M` or `08:00,512k 19:00,off`
+    #[serde(default)]
+    rclone_bwlimit: Option<String>,
+    #[serde(default)]
+    rclone_extra_args: Option<Vec<String>>,
+    // Subfolder of rclone_path to sync to, such as `{year}/{month}`
+    #[serde(default)]
+    remote_path_template: Option<String>,
+    #[serde(default)]
+    verify_remote_sync: bool,
+    #[serde(default)]
+    verbose_backup: bool,
+    #[serde(default)]
+    dry_run: bool,
     flush_on_save: bool,
     silent: bool,
+    #[serde(default)]
+    snapshot_mode: Option<SnapshotMode>,
+    #[serde(default)]
+    save_strategy: BackupSaveStrategy,
+    #[serde(default)]
+    save_poll_timeout_secs: Option<u64>,
+    #[serde(default)]
+    confirm_save_off: bool,
+    #[serde(default)]
+    max_backup_duration_mins: Option<u64>,
+    #[serde(default)]
+    command_timeout_mins: Option<u64>,
+    #[serde(default)]
+    backup_warning_secs: Option<u64>,
+    #[serde(default = "default_true")]
+    backup_failure_fatal: bool,
+    #[serde(default)]
+    min_free_space_gb: Option<u64>,
+    #[serde(default)]
+    notify_slow_backups: bool,
+    #[serde(default)]
+    verify_region_checksums: bool,
+    #[serde(default = "default_backup_nice")]
+    backup_nice: Option<i32>,
+    #[serde(default = "default_backup_ionice_class")]
+    backup_ionice_class: Option<u8>,
+    #[serde(default)]
+    duplicity_volsize_mb: Option<u32>,
+    #[serde(default)]
+    duplicity_extra_args: Option<Vec<String>>,
+    #[serde(default)]
+    backup_excludes: Vec<String>,
+    #[serde(default)]
+    duplicity_encryption: Option<DuplicityEncryption>,
+    #[serde(default)]
+    extra_backup_paths: Vec<String>,
+}
+
+// Backup tools are wrapped in `nice` and `ionice` with these values, None
+// leaving the corresponding priority unchanged
+#[derive(Clone, Copy)]
+pub struct BackupPriority {
+    pub nice: Option<i32>,
+    pub ionice_class: Option<u8>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum DuplicityEncryption {
+    // Symmetric encryption, the passphrase is given to duplicity through its environment
+    Passphrase(String),
+    // Encryption for a key of the gpg keyring, with gpg-agent handling the secret key
+    GpgAgent(String),
+}
+
+#[derive(Clone)]
+pub enum BackupTarget {
+    Local(PathBuf),
+    // A URL handled natively by duplicity, passed to it as is
+    Remote(String),
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub enum SnapshotMode {
+    Btrfs,
+    Zfs,
+}
+
+// Which of disabling saving and saving the world are done around backups
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BackupSaveStrategy {
+    #[default]
+    SaveOffAndFlush,
+    SaveOffOnly,
+    // For servers that save chunks safely while backing up, such as Paper
+    FlushOnly,
+    None,
+}
+
+impl BackupSaveStrategy {
+    pub fn disables_saving(self) -> bool {
+        matches!(self, Self::SaveOffAndFlush | Self::SaveOffOnly)
+    }
+
+    pub fn saves(self) -> bool {
+        matches!(self, Self::SaveOffAndFlush | Self::FlushOnly)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ShutdownMethod {
+    // Kept as the default so that existing configs keep stopping the server
+    // the way they used to, RCON first has to be chosen explicitly
+    #[default]
+    Signal,
+    RconStop,
+    // Stop command through RCON, then the shutdown signal if the server is still running
+    RconThenSignal,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+pub enum ShutdownSignal {
+    #[default]
+    Sigint,
+    Sigterm,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+pub enum BroadcastStyle {
+    #[default]
+    Tellraw,
+    Say,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TextComponent {
+    // `{message}` is replaced by the broadcast message
+    pub text: String,
+    #[serde(default)]
+    pub color: Option<String>,
+    #[serde(default)]
+    pub bold: bool,
+    #[serde(default)]
+    pub italic: bool,
+}
+
+// Commands sent through RCON for each operation, Minecraft's by default
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct CommandProfile {
+    pub save_on: String,
+    pub save_off: String,
+    pub save_all: String,
+    pub save_all_flush: String,
+    // `{message}` is replaced by the message. Without it, messages are sent
+    // according to `broadcast_style` and `broadcast_format`.
+    pub broadcast: Option<String>,
+    pub stop: String,
+    pub list: String,
+    // Queried for `server-manager status`
+    pub seed: String,
+    pub difficulty: String,
+    pub time_query: String,
+}
+
+impl Default for CommandProfile {
+    fn default() -> Self {
+        Self {
+            save_on: "save-on".into(),
+            save_off: "save-off".into(),
+            save_all: "save-all".into(),
+            save_all_flush: "save-all flush".into(),
+            broadcast: None,
+            stop: "stop".into(),
+            list: "list".into(),
+            seed: "seed".into(),
+            difficulty: "difficulty".into(),
+            time_query: "time query daytime".into(),
+        }
+    }
+}
+
+fn default_broadcast_format() -> Vec<TextComponent> {
+    vec![TextComponent {
+        text: "{message}".into(),
+        color: Some("light_purple".into()),
+        bold: false,
+        italic: false,
+    }]
 }
 
+const MINECRAFT_COLORS: [&str; 16] = [
+    "black",
+    "dark_blue",
+    "dark_green",
+    "dark_aqua",
+    "dark_red",
+    "dark_purple",
+    "gold",
+    "gray",
+    "dark_gray",
+    "blue",
+    "green",
+    "aqua",
+    "red",
+    "light_purple",
+    "yellow",
+    "white",
+];
+
 #[derive(Serialize, Deserialize)]
 pub struct MailConfigSerialized {
     contacts: Vec<String>,
+    #[serde(default)]
+    cc: Vec<String>,
+    #[serde(default)]
+    reply_to: Option<String>,
+    #[serde(default)]
+    mail_transport: MailTransport,
+    // Only used with the SMTP transport
+    #[serde(default)]
     smtp_server: String,
     sender: String,
+    // Shown instead of the name given in `sender`, if any
+    #[serde(default)]
+    sender_name: Option<String>,
+    #[serde(default)]
     username: String,
+    #[serde(default)]
     password: String,
+    #[serde(default = "default_true")]
+    send_startup_mail: bool,
+    #[serde(default)]
+    strict_mail: bool,
+    #[serde(default = "default_severities")]
+    severities: Vec<NotificationSeverity>,
+    #[serde(default)]
+    mail_templates: MailTemplatesSerialized,
+}
+
+// `{name}`, `{time}` and `{version}` are replaced in all templates, and
+// `{report}` by the error reports in the incident body
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct MailTemplatesSerialized {
+    startup_subject: Option<String>,
+    startup_body: Option<String>,
+    incident_subject: Option<String>,
+    final_incident_subject: Option<String>,
+    incident_body: Option<String>,
+    warning_subject: Option<String>,
+    warning_body: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct WebhookConfigSerialized {
+    url: String,
+    #[serde(default = "default_webhook_method")]
+    method: String,
+    #[serde(default)]
+    content_type: WebhookContentType,
+    #[serde(default)]
+    headers: Vec<(String, String)>,
+    // `{subject}`, `{body}` and `{severity}` are replaced by the notification
+    #[serde(default)]
+    body: Option<String>,
+    #[serde(default = "default_true")]
+    send_startup: bool,
+    #[serde(default)]
+    send_ready: bool,
+    #[serde(default = "default_severities")]
+    severities: Vec<NotificationSeverity>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SlackConfigSerialized {
+    url: String,
+    // Channel to post to instead of the default one of the webhook
+    #[serde(default)]
+    channel: Option<String>,
+    #[serde(default = "default_true")]
+    send_startup: bool,
+    #[serde(default)]
+    send_ready: bool,
+    #[serde(default = "default_severities")]
+    severities: Vec<NotificationSeverity>,
+}
+
+// Which events a notifier is sent. `Info` covers the startup and ready
+// notifications.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum NotificationSeverity {
+    Info,
+    Incident,
+    FinalIncident,
+    // Sent when incidents keep happening, see `warn_incidents`
+    Warning,
+}
+
+impl NotificationSeverity {
+    pub fn of_report(final_incident: bool) -> Self {
+        if final_incident {
+            NotificationSeverity::FinalIncident
+        } else {
+            NotificationSeverity::Incident
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+pub enum MailTransport {
+    #[default]
+    Smtp,
+    // Pipes mails to the local `sendmail` binary
+    Sendmail,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+pub enum WebhookContentType {
+    #[default]
+    Json,
+    Form,
+}
+
+fn default_webhook_method() -> String {
+    "POST".into()
+}
+
+// Accepts the JVM syntax for memory amounts, such as `4G` or `4096M`
+fn parse_memory_mb(memory: &str) -> Option<u64> {
+    let unit_start = memory
+        .len()
+        .checked_sub(1)
+        .filter(|&i| memory.is_char_boundary(i))?;
+    let (amount, unit) = memory.split_at(unit_start);
+    let amount: u64 = amount.parse().ok()?;
+    let mb = match unit {
+        "k" | "K" => amount / 1024,
+        "m" | "M" => amount,
+        "g" | "G" => amount.checked_mul(1024)?,
+        _ => return None,
+    };
+    (mb > 0).then_some(mb)
+}
+
+// Removes Minecraft formatting codes, a section sign followed by a character
+fn strip_formatting(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '§' {
+            chars.next();
+        } else {
+            res.push(c);
+        }
+    }
+    res
+}
+
+fn builtin_jvm_preset(name: &str, memory_mb: Option<u64>) -> Option<Vec<String>> {
+    let args: Vec<&str> = match name {
+        "vanilla" => Vec::new(),
+        // See https://docs.papermc.io/paper/aikars-flags
+        "aikar" => {
+            let large_heap = memory_mb.is_some_and(|mb| mb > 12 * 1024);
+            let mut args = vec![
+                "-XX:+UseG1GC",
+                "-XX:+ParallelRefProcEnabled",
+                "-XX:MaxGCPauseMillis=200",
+                "-XX:+UnlockExperimentalVMOptions",
+                "-XX:+DisableExplicitGC",
+                "-XX:+AlwaysPreTouch",
+            ];
+            if large_heap {
+                args.extend([
+                    "-XX:G1NewSizePercent=40",
+                    "-XX:G1MaxNewSizePercent=50",
+                    "-XX:G1HeapRegionSize=16M",
+                    "-XX:G1ReservePercent=15",
+                ]);
+            } else {
+                args.extend([
+                    "-XX:G1NewSizePercent=30",
+                    "-XX:G1MaxNewSizePercent=40",
+                    "-XX:G1HeapRegionSize=8M",
+                    "-XX:G1ReservePercent=20",
+                ]);
+            }
+            args.extend([
+                "-XX:G1HeapWastePercent=5",
+                "-XX:G1MixedGCCountTarget=4",
+                if large_heap {
+                    "-XX:InitiatingHeapOccupancyPercent=20"
+                } else {
+                    "-XX:InitiatingHeapOccupancyPercent=15"
+                },
+                "-XX:G1MixedGCLiveThresholdPercent=90",
+                "-XX:G1RSetUpdatingPauseTimePercent=5",
+                "-XX:SurvivorRatio=32",
+                "-XX:+PerfDisableSharedMem",
+                "-XX:MaxTenuringThreshold=1",
+                "-Dusing.aikars.flags=https://mcflags.emc.gs",
+                "-Daikars.new.flags=true",
+            ]);
+            args
+        }
+        _ => return None,
+    };
+
+    Some(args.into_iter().map(Into::into).collect())
+}
+
+fn default_severities() -> Vec<NotificationSeverity> {
+    vec![
+        NotificationSeverity::Info,
+        NotificationSeverity::Incident,
+        NotificationSeverity::FinalIncident,
+        NotificationSeverity::Warning,
+    ]
+}
+
+fn default_backup_nice() -> Option<i32> {
+    Some(10)
+}
+
+// Idle class, only given disk time when no other process needs it
+fn default_backup_ionice_class() -> Option<u8> {
+    Some(3)
+}
+
+fn default_health_fail_threshold() -> u32 {
+    3
+}
+
+fn default_rcon_host() -> String {
+    "localhost".into()
+}
+
+fn default_command_queue_size() -> usize {
+    32
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    20
+}
+
+fn default_shutdown_announcement() -> Option<String> {
+    Some("Server restarting due to maintenance.".into())
+}
+
+fn default_wake_motd() -> String {
+    "Server is sleeping, join to wake it up!".into()
+}
+
+const TIMEZONE_DATABASE: &str = "/usr/share/zoneinfo";
+
+fn default_failure_exit_code() -> u8 {
+    1
+}
+
+fn default_true() -> bool {
+    true
 }
 
 impl ConfigSerialized {
+    // Only validated along with the rest of the config
+    pub fn timezone(&self) -> Option<&str> {
+        self.timezone.as_deref()
+    }
+
     pub fn save(&self, path: &Path) -> Result<()> {
         let file = std::fs::File::create(path)?;
         ron::ser::to_writer_pretty(file, self, Default::default())?;
@@ -63,13 +637,58 @@ impl Default for ConfigSerialized {
         Self {
             name: "Minecraft Server".into(),
             auto_restart: true,
+            restart_on_clean_exit: true,
             server_folder: "./".into(),
             server_jar: "minecraft_server.jar".into(),
+            working_dir: None,
+            run_as_user: None,
+            run_as_group: None,
             java: "java".into(),
+            java_home: None,
             java_args: Vec::new(),
+            java_args_file: None,
+            memory: None,
+            jvm_preset: None,
+            jvm_presets: HashMap::new(),
             rcon_password: base64::encode(rcon_key),
             rcon_port: 25575,
+            rcon_host: default_rcon_host(),
+            rcon_minecraft_quirks: true,
+            read_rcon_from_properties: false,
+            ensure_rcon_enabled: false,
+            properties_overrides: None,
+            broadcast_style: BroadcastStyle::Tellraw,
+            broadcast_format: default_broadcast_format(),
+            command_profile: Default::default(),
+            command_queue_size: default_command_queue_size(),
+            idle_shutdown_mins: None,
+            restart_after_idle: false,
+            restart_memory_threshold_mb: None,
+            drain_timeout_mins: None,
+            drain_whitelist: false,
+            stable_after_secs: None,
+            warn_incidents: 0,
+            timezone: None,
+            incident_log_lines: 0,
+            ready_pattern: None,
+            log_level: LogLevel::Info,
+            log_name: None,
+            use_motd_as_name: false,
+            on_connect_commands: Vec::new(),
+            health_check_interval_secs: None,
+            health_check_command: None,
+            health_fail_threshold: default_health_fail_threshold(),
+            shutdown_method: ShutdownMethod::Signal,
+            shutdown_signal: ShutdownSignal::Sigint,
+            shutdown_timeout_secs: default_shutdown_timeout_secs(),
+            shutdown_announcement: default_shutdown_announcement(),
+            wake_on_connect: false,
+            wake_motd: default_wake_motd(),
+            restart_on_update: false,
+            failure_exit_code: default_failure_exit_code(),
             mailing: None,
+            webhook: None,
+            slack: None,
             backups: Some(Default::default()),
         }
     }
@@ -81,27 +700,116 @@ impl Default for BackupConfigSerialized {
             backup_folder: "./backups".into(),
             world_folder: "world".into(),
             incremental_freq_hours: 1,
+            backup_jitter_secs: 0,
+            initial_backup_delay_mins: None,
+            catch_up_backups: true,
             full_backup_every: 24 * 14,
             keep_full_backup: 2,
             rclone_path: None,
+            check_rclone_write: true,
+            rclone_transfers: None,
+            rclone_bwlimit: None,
+            rclone_extra_args: None,
+            remote_path_template: None,
+            verify_remote_sync: false,
+            verbose_backup: false,
+            dry_run: false,
             flush_on_save: true,
             silent: false,
+            snapshot_mode: None,
+            save_strategy: BackupSaveStrategy::SaveOffAndFlush,
+            save_poll_timeout_secs: None,
+            confirm_save_off: false,
+            max_backup_duration_mins: None,
+            command_timeout_mins: None,
+            backup_warning_secs: None,
+            backup_failure_fatal: true,
+            min_free_space_gb: None,
+            notify_slow_backups: false,
+            verify_region_checksums: false,
+            backup_nice: default_backup_nice(),
+            backup_ionice_class: default_backup_ionice_class(),
+            duplicity_volsize_mb: None,
+            duplicity_extra_args: None,
+            backup_excludes: Vec::new(),
+            duplicity_encryption: None,
+            extra_backup_paths: Vec::new(),
         }
     }
 }
 
+// A command periodically sent through RCON to detect a hung server
+#[derive(Clone)]
+pub struct HealthCheck {
+    pub command: String,
+    pub interval: Duration,
+    pub fail_threshold: u32,
+}
+
+// Players are asked to leave before a planned restart
+#[derive(Clone)]
+pub struct DrainConfig {
+    pub timeout: Duration,
+    pub whitelist: bool,
+}
+
+// The identity the server runs as, when it differs from the manager's
+#[derive(Clone)]
+pub struct RunAs {
+    // The user of the manager is kept if None
+    pub user: Option<User>,
+    pub gid: Gid,
+    // Supplementary groups, which replace those of the manager
+    pub groups: Vec<Gid>,
+}
+
 #[derive(Clone)]
 pub struct Config {
     pub name: String,
     pub auto_restart: bool,
+    pub restart_on_clean_exit: bool,
     pub server_folder: PathBuf,
     pub server_jar: PathBuf,
+    pub working_dir: PathBuf,
+    pub run_as: Option<RunAs>,
     pub backups: Option<BackupConfig>,
     pub rcon_password: String,
     pub rcon_port: u16,
-    pub java: String,
+    pub rcon_host: String,
+    pub rcon_minecraft_quirks: bool,
+    pub ensure_rcon_enabled: bool,
+    pub properties_overrides: Vec<(String, String)>,
+    pub java: PathBuf,
+    pub java_home: Option<PathBuf>,
     pub java_args: Vec<String>,
+    pub broadcast_style: BroadcastStyle,
+    pub broadcast_format: Vec<TextComponent>,
+    pub command_profile: CommandProfile,
+    pub command_queue_size: usize,
+    pub idle_shutdown: Option<Duration>,
+    pub restart_after_idle: bool,
+    pub restart_memory_threshold_mb: Option<u64>,
+    pub drain: Option<DrainConfig>,
+    pub stable_after: Option<Duration>,
+    pub warn_incidents: u32,
+    pub timezone: Option<String>,
+    pub incident_log_lines: usize,
+    pub ready_pattern: Option<String>,
+    pub log_level: LogLevel,
+    pub log_name: String,
+    pub on_connect_commands: Vec<String>,
+    pub health_check: Option<HealthCheck>,
+    pub shutdown_method: ShutdownMethod,
+    pub shutdown_signal: ShutdownSignal,
+    pub shutdown_timeout: Duration,
+    pub shutdown_announcement: Option<String>,
+    pub wake_on_connect: bool,
+    pub wake_motd: String,
+    pub restart_on_update: bool,
+    pub failure_exit_code: u8,
     pub mailing: Option<MailConfig>,
+    pub webhook: Option<WebhookConfig>,
+    pub slack: Option<SlackConfig>,
 }
 
 impl Config {
@@ -126,8 +834,194 @@ impl Config {
             )
         })?;
 
+        let working_dir = match &value.working_dir {
+            Some(path) => {
+                let working_dir = std::fs::canonicalize(path).map_err(|_| {
+                    anyhow!("failed to find working directory at {}", path.display())
+                })?;
+
+                if !working_dir.is_dir() {
+                    return Err(anyhow!(
+                        "the working directory at {} is not a folder",
+                        path.display()
+                    ));
+                }
+
+                working_dir
+            }
+            None => server_folder.clone(),
+        };
+
+        let run_as = Self::run_as(value.run_as_user, value.run_as_group)?;
+
+        if value.read_rcon_from_properties && value.ensure_rcon_enabled {
+            return Err(anyhow!(
+                "read_rcon_from_properties and ensure_rcon_enabled cannot be used together"
+            ));
+        }
+
+        let mut properties_overrides: Vec<_> = value
+            .properties_overrides
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+        properties_overrides.sort();
+
+        for (key, _) in &properties_overrides {
+            if key.is_empty() {
+                return Err(anyhow!("properties_overrides contains an empty key"));
+            }
+
+            let is_rcon_key = matches!(key.as_str(), "enable-rcon" | "rcon.port" | "rcon.password");
+            if is_rcon_key && (value.read_rcon_from_properties || value.ensure_rcon_enabled) {
+                return Err(anyhow!(
+                    "properties_overrides cannot set `{}` when RCON settings are read from or written to server.properties",
+                    key
+                ));
+            }
+        }
+
+        let (rcon_port, rcon_password) = if value.read_rcon_from_properties {
+            Self::rcon_from_properties(&server_folder)?
+        } else {
+            (value.rcon_port, value.rcon_password)
+        };
+
+        // Brackets around IPv6 literals are accepted but not needed
+        let rcon_host = match value
+            .rcon_host
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+        {
+            Some(host) if host.parse::<Ipv6Addr>().is_ok() => host.to_string(),
+            _ => value.rcon_host.clone(),
+        };
+
+        let valid_host = rcon_host.parse::<IpAddr>().is_ok()
+            || (!rcon_host.is_empty()
+                && !rcon_host.contains(|c: char| "[]:".contains(c) || c.is_whitespace()));
+        if !valid_host {
+            return Err(anyhow!("invalid RCON host `{}`", value.rcon_host));
+        }
+
+        Self::check_broadcast_format(&value.broadcast_format)?;
+        Self::check_command_profile(&value.command_profile)?;
+
+        if let Some(timezone) = &value.timezone {
+            // Time zones are looked up in the system database by name, e.g. "Europe/Paris"
+            let known = !timezone.is_empty()
+                && !timezone
+                    .split('/')
+                    .any(|part| part.is_empty() || part == "..")
+                && Path::new(TIMEZONE_DATABASE).join(timezone).is_file();
+            if !known {
+                return Err(anyhow!("unknown time zone {}", timezone));
+            }
+        }
+
+        let (java, java_home) = Self::resolve_java(value.java, value.java_home)?;
+
+        if let Some(pattern) = &value.ready_pattern {
+            if pattern.chars().all(|c| c == '*') {
+                return Err(anyhow!("ready pattern `{}` matches any line", pattern));
+            }
+            if pattern.contains(".*") || pattern.contains('\\') {
+                return Err(anyhow!(
+                    "ready pattern `{}` looks like a regular expression, only `*` matches any text",
+                    pattern
+                ));
+            }
+        }
+
+        let health_check = match value.health_check_interval_secs {
+            Some(0) => return Err(anyhow!("health_check_interval_secs must not be 0")),
+            Some(secs) => {
+                let command = value
+                    .health_check_command
+                    .unwrap_or_else(|| value.command_profile.list.clone());
+                if command.trim().is_empty() {
+                    return Err(anyhow!("health_check_command must not be empty"));
+                }
+
+                if value.health_fail_threshold == 0 {
+                    return Err(anyhow!("health_fail_threshold must not be 0"));
+                }
+
+                Some(HealthCheck {
+                    command,
+                    interval: Duration::from_secs(secs),
+                    fail_threshold: value.health_fail_threshold,
+                })
+            }
+            None => None,
+        };
+
+        // Arguments from the file come first, so that inline ones can override them
+        let mut java_args = match &value.java_args_file {
+            Some(path) => Self::read_java_args_file(path)?,
+            None => Vec::new(),
+        };
+        java_args.extend(value.java_args);
+
+        let java_args = Self::build_java_args(
+            value.jvm_preset.as_deref(),
+            &value.jvm_presets,
+            value.memory.as_deref(),
+            java_args,
+        )?;
+
+        if value.command_queue_size == 0 {
+            return Err(anyhow!("command queue size must not be zero"));
+        }
+
+        if value.warn_incidents > MAX_RECENT_INCIDENTS {
+            return Err(anyhow!(
+                "warn_incidents must be at most {}, the server manager stops after more incidents",
+                MAX_RECENT_INCIDENTS
+            ));
+        }
+
+        if value.failure_exit_code == 0 {
+            return Err(anyhow!("failure_exit_code must not be zero"));
+        }
+
+        if value.idle_shutdown_mins == Some(0) {
+            return Err(anyhow!("idle shutdown delay must not be zero"));
+        }
+
+        if value.restart_memory_threshold_mb == Some(0) {
+            return Err(anyhow!("restart_memory_threshold_mb must not be zero"));
+        }
+
+        let drain = match value.drain_timeout_mins {
+            Some(0) => return Err(anyhow!("drain_timeout_mins must not be zero")),
+            Some(mins) => Some(DrainConfig {
+                timeout: Duration::from_secs(mins * 60),
+                whitelist: value.drain_whitelist,
+            }),
+            None if value.drain_whitelist => {
+                return Err(anyhow!(
+                    "drain_whitelist requires drain_timeout_mins to be set"
+                ))
+            }
+            None => None,
+        };
+
+        // Memory usage is read from /proc
+        let restart_memory_threshold_mb = if cfg!(target_os = "linux") {
+            value.restart_memory_threshold_mb
+        } else {
+            if value.restart_memory_threshold_mb.is_some() {
+                warn!("restart_memory_threshold_mb is only supported on Linux, ignoring it.");
+            }
+            None
+        };
+
         let backups = if let Some(backups) = value.backups {
-            Some(BackupConfig::try_from_serialized(backups, &server_folder).await?)
+            Some(
+                BackupConfig::try_from_serialized(backups, &server_folder, &value.command_profile)
+                    .await?,
+            )
         } else {
             None
         };
@@ -138,42 +1032,362 @@ impl Config {
             None
         };
 
+        let webhook = if let Some(webhook) = value.webhook {
+            Some(WebhookConfig::try_from_serialized(webhook).await?)
+        } else {
+            None
+        };
+
+        let slack = if let Some(slack) = value.slack {
+            Some(SlackConfig::try_from_serialized(slack).await?)
+        } else {
+            None
+        };
+
+        let name = if value.use_motd_as_name {
+            Self::name_from_properties(&server_folder).unwrap_or(value.name)
+        } else {
+            value.name
+        };
+
+        let log_name = value.log_name.unwrap_or_else(|| name.clone());
+
         Ok(Self {
-            name: value.name,
+            name,
             auto_restart: value.auto_restart,
+            restart_on_clean_exit: value.restart_on_clean_exit,
             server_folder,
             server_jar,
+            working_dir,
+            run_as,
             backups,
-            rcon_password: value.rcon_password,
-            rcon_port: value.rcon_port,
-            java: value.java,
-            java_args: value.java_args,
+            rcon_password,
+            rcon_port,
+            rcon_host,
+            rcon_minecraft_quirks: value.rcon_minecraft_quirks,
+            ensure_rcon_enabled: value.ensure_rcon_enabled,
+            properties_overrides,
+            java,
+            java_home,
+            java_args,
+            broadcast_style: value.broadcast_style,
+            broadcast_format: value.broadcast_format,
+            command_profile: value.command_profile,
+            command_queue_size: value.command_queue_size,
+            idle_shutdown: value
+                .idle_shutdown_mins
+                .map(|mins| Duration::from_secs(mins * 60)),
+            restart_after_idle: value.restart_after_idle,
+            restart_memory_threshold_mb,
+            drain,
+            stable_after: value.stable_after_secs.map(Duration::from_secs),
+            warn_incidents: value.warn_incidents,
+            timezone: value.timezone,
+            incident_log_lines: value.incident_log_lines,
+            ready_pattern: value.ready_pattern,
+            log_level: value.log_level,
+            log_name,
+            on_connect_commands: value.on_connect_commands,
+            health_check,
+            shutdown_method: value.shutdown_method,
+            shutdown_signal: value.shutdown_signal,
+            shutdown_timeout: Duration::from_secs(value.shutdown_timeout_secs),
+            shutdown_announcement: value.shutdown_announcement,
+            wake_on_connect: value.wake_on_connect,
+            wake_motd: value.wake_motd,
+            restart_on_update: value.restart_on_update,
+            failure_exit_code: value.failure_exit_code,
             mailing,
+            webhook,
+            slack,
         })
     }
 
+    // A bare java name is looked up in the JDK rather than in PATH when
+    // java_home is set.
+    fn resolve_java(
+        java: String,
+        java_home: Option<PathBuf>,
+    ) -> Result<(PathBuf, Option<PathBuf>)> {
+        let java_home = match java_home {
+            Some(java_home) => java_home,
+            None => return Ok((java.into(), None)),
+        };
+
+        // The server runs in its own folder, so relative paths are resolved here
+        let java_home = std::fs::canonicalize(&java_home)
+            .map_err(|err| anyhow!("invalid java_home {}: {}", java_home.display(), err))?;
+
+        let bundled = java_home.join("bin").join("java");
+        if !bundled.is_file() {
+            return Err(anyhow!(
+                "java_home {} is not a JDK, it does not contain bin/java",
+                java_home.display()
+            ));
+        }
+
+        let java = if java.contains('/') {
+            java.into()
+        } else {
+            java_home.join("bin").join(java)
+        };
+
+        Ok((java, Some(java_home)))
+    }
+
+    // One argument per line, ignoring empty lines and `#` comments
+    fn read_java_args_file(path: &Path) -> Result<Vec<String>> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| anyhow!("failed to read java args file {}: {}", path.display(), err))?;
+
+        Ok(content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(String::from)
+            .collect())
+    }
+
+    // Presets come first, then memory settings, then explicit arguments, so
+    // that later arguments override earlier ones for the JVM.
+    fn build_java_args(
+        preset: Option<&str>,
+        presets: &HashMap<String, Vec<String>>,
+        memory: Option<&str>,
+        java_args: Vec<String>,
+    ) -> Result<Vec<String>> {
+        let memory_mb = match memory {
+            Some(memory) => Some(
+                parse_memory_mb(memory)
+                    .ok_or_else(|| anyhow!("invalid memory amount `{}`", memory))?,
+            ),
+            None => None,
+        };
+
+        let mut args = match preset {
+            Some(name) => {
+                if let Some(user_preset) = presets.get(name) {
+                    let mut args = Vec::with_capacity(user_preset.len());
+                    for arg in user_preset {
+                        if arg.contains("{memory}") {
+                            let memory = memory.ok_or_else(|| {
+                                anyhow!("JVM preset {} requires `memory` to be set", name)
+                            })?;
+                            args.push(arg.replace("{memory}", memory));
+                        } else {
+                            args.push(arg.clone());
+                        }
+                    }
+                    args
+                } else {
+                    builtin_jvm_preset(name, memory_mb)
+                        .ok_or_else(|| anyhow!("unknown JVM preset {}", name))?
+                }
+            }
+            None => Vec::new(),
+        };
+
+        if let Some(memory) = memory {
+            args.push(format!("-Xms{}", memory));
+            args.push(format!("-Xmx{}", memory));
+        }
+
+        args.extend(java_args);
+        Ok(args)
+    }
+
+    fn check_command_profile(profile: &CommandProfile) -> Result<()> {
+        let commands = [
+            ("save_on", &profile.save_on),
+            ("save_off", &profile.save_off),
+            ("save_all", &profile.save_all),
+            ("save_all_flush", &profile.save_all_flush),
+            ("stop", &profile.stop),
+            ("list", &profile.list),
+            ("seed", &profile.seed),
+            ("difficulty", &profile.difficulty),
+            ("time_query", &profile.time_query),
+        ];
+        for (name, command) in commands {
+            if command.trim().is_empty() {
+                return Err(anyhow!("{} command must not be empty", name));
+            }
+        }
+
+        if let Some(broadcast) = &profile.broadcast {
+            if !broadcast.contains("{message}") {
+                return Err(anyhow!(
+                    "broadcast command must contain a `{{message}}` placeholder"
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_broadcast_format(format: &[TextComponent]) -> Result<()> {
+        if !format.iter().any(|c| c.text.contains("{message}")) {
+            return Err(anyhow!(
+                "broadcast format must contain a `{{message}}` placeholder"
+            ));
+        }
+
+        for color in format.iter().filter_map(|c| c.color.as_ref()) {
+            let is_hex = color.len() == 7
+                && color.starts_with('#')
+                && color[1..].chars().all(|c| c.is_ascii_hexdigit());
+
+            if !is_hex && !MINECRAFT_COLORS.contains(&color.as_str()) {
+                return Err(anyhow!("invalid broadcast color `{}`", color));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn rcon_from_properties(server_folder: &Path) -> Result<(u16, String)> {
+        let properties = Properties::load(&server_folder.join("server.properties"))?;
+
+        if properties.get("enable-rcon") != Some("true") {
+            return Err(anyhow!(
+                "config requests reading RCON settings from server.properties but RCON is not enabled there"
+            ));
+        }
+
+        let port = match properties.get("rcon.port") {
+            Some(port) => port
+                .parse()
+                .map_err(|_| anyhow!("invalid rcon.port `{}` in server.properties", port))?,
+            None => 25575,
+        };
+
+        let password = match properties.get("rcon.password") {
+            Some(password) if !password.is_empty() => password.to_string(),
+            _ => {
+                return Err(anyhow!(
+                    "config requests reading RCON settings from server.properties but no rcon.password is set there"
+                ))
+            }
+        };
+
+        Ok((port, password))
+    }
+
+    fn run_as(user: Option<String>, group: Option<String>) -> Result<Option<RunAs>> {
+        if user.is_none() && group.is_none() {
+            return Ok(None);
+        }
+
+        if !geteuid().is_root() {
+            return Err(anyhow!(
+                "run_as_user and run_as_group require server-manager to run as root"
+            ));
+        }
+
+        let user = match user {
+            Some(name) => Some(
+                User::from_name(&name)?
+                    .ok_or_else(|| anyhow!("unknown user `{}` in run_as_user", name))?,
+            ),
+            None => None,
+        };
+
+        let gid = match (group, &user) {
+            (Some(name), _) => {
+                Group::from_name(&name)?
+                    .ok_or_else(|| anyhow!("unknown group `{}` in run_as_group", name))?
+                    .gid
+            }
+            (None, Some(user)) => user.gid,
+            (None, None) => unreachable!(),
+        };
+
+        let groups = match &user {
+            Some(user) => getgrouplist(&CString::new(user.name.as_str())?, gid)?,
+            None => vec![gid],
+        };
+
+        Ok(Some(RunAs { user, gid, groups }))
+    }
+
+    // The properties file only exists once the server ran, so the configured
+    // name is kept if it cannot be read.
+    fn name_from_properties(server_folder: &Path) -> Option<String> {
+        let properties = match Properties::load(&server_folder.join("server.properties")) {
+            Ok(properties) => properties,
+            Err(err) => {
+                warn!(
+                    "Failed to read the MOTD, using the configured name:\n{}",
+                    err
+                );
+                return None;
+            }
+        };
+
+        let motd = strip_formatting(properties.get("motd")?);
+        let motd = motd.split_whitespace().collect::<Vec<_>>().join(" ");
+        (!motd.is_empty()).then_some(motd)
+    }
+
     pub async fn try_from(path: &Path) -> Result<Self> {
         let config_ser: ConfigSerialized = ron::de::from_reader(std::fs::File::open(path)?)?;
         Self::try_from_serialized(config_ser).await
     }
+
+    pub async fn try_from_str(text: &str) -> Result<Self> {
+        let config_ser: ConfigSerialized = ron::de::from_str(text)?;
+        Self::try_from_serialized(config_ser).await
+    }
 }
 
 #[derive(Clone)]
 pub struct BackupConfig {
-    pub backup_folder: PathBuf,
+    pub backup_target: BackupTarget,
+    pub server_folder: PathBuf,
     pub world_folder: PathBuf,
     pub incremental: Duration,
+    pub backup_jitter: Duration,
+    // A full backup interval if None
+    pub initial_backup_delay: Option<Duration>,
+    pub catch_up_backups: bool,
     pub full_backup_every: u32,
     pub keep_full_backup: u32,
     pub rclone_path: Option<String>,
+    pub rclone_transfers: Option<u32>,
+    pub rclone_bwlimit: Option<String>,
+    pub rclone_extra_args: Vec<String>,
+    pub remote_path_template: Option<String>,
+    pub verify_remote_sync: bool,
+    pub verbose_backup: bool,
+    pub dry_run: bool,
     pub flush_on_save: bool,
     pub silent: bool,
+    pub snapshot_mode: Option<SnapshotMode>,
+    pub save_strategy: BackupSaveStrategy,
+    pub save_poll_timeout: Option<Duration>,
+    // Sent from the command profile while polling for save completion
+    pub save_command: String,
+    pub confirm_save_off: bool,
+    pub max_backup_duration: Option<Duration>,
+    pub command_timeout: Option<Duration>,
+    pub backup_warning: Option<Duration>,
+    pub backup_failure_fatal: bool,
+    pub min_free_space_gb: Option<u64>,
+    pub notify_slow_backups: bool,
+    pub verify_region_checksums: bool,
+    pub priority: BackupPriority,
+    pub duplicity_volsize_mb: Option<u32>,
+    pub duplicity_extra_args: Vec<String>,
+    pub backup_excludes: Vec<String>,
+    pub duplicity_encryption: Option<DuplicityEncryption>,
+    pub extra_backup_paths: Vec<PathBuf>,
 }
 
 impl BackupConfig {
     pub async fn try_from_serialized(
         config: BackupConfigSerialized,
         server_folder: &Path,
+        commands: &CommandProfile,
     ) -> Result<Self> {
         if !Duplicity::is_available().await? {
             return Err(anyhow!(
@@ -181,30 +1395,64 @@ impl BackupConfig {
             ));
         }
 
-        let mut backup_folder = std::fs::canonicalize(&config.backup_folder)
-            .map_err(|_| anyhow!("failed to find backup folder at {}", config.backup_folder))?;
+        let backup_target = if config.backup_folder.contains("://") {
+            BackupTarget::Remote(Self::check_remote_target(&config.backup_folder)?)
+        } else {
+            let mut backup_folder = std::fs::canonicalize(&config.backup_folder)
+                .map_err(|_| anyhow!("failed to find backup folder at {}", config.backup_folder))?;
 
-        if backup_folder.ends_with("/") {
-            backup_folder.pop();
-        }
+            if backup_folder.ends_with("/") {
+                backup_folder.pop();
+            }
 
-        if !backup_folder.is_dir() {
-            return Err(anyhow!(
-                "backup folder `{:?}` is not a folder",
-                backup_folder
-            ));
-        }
+            if !backup_folder.is_dir() {
+                return Err(anyhow!(
+                    "backup folder `{:?}` is not a folder",
+                    backup_folder
+                ));
+            }
+
+            BackupTarget::Local(backup_folder)
+        };
 
         let mut world_folder = server_folder.join(config.world_folder);
         if world_folder.ends_with("/") {
             world_folder.pop();
         }
 
+        if let BackupTarget::Local(backup_folder) = &backup_target {
+            // The world may not have been generated yet, in which case its path is
+            // only made of the canonical server folder and the relative world path
+            let world_canonical =
+                std::fs::canonicalize(&world_folder).unwrap_or_else(|_| world_folder.clone());
+            if world_canonical.starts_with(backup_folder)
+                || backup_folder.starts_with(&world_canonical)
+            {
+                return Err(anyhow!(
+                    "backup folder {:?} and world folder {:?} must not be inside one another",
+                    backup_folder,
+                    world_canonical
+                ));
+            }
+        }
+
         if config.incremental_freq_hours == 0 {
             return Err(anyhow!("incremental backup frequency must not be zero"));
         }
 
+        if config.backup_jitter_secs >= config.incremental_freq_hours * 60 * 60 {
+            return Err(anyhow!(
+                "backup jitter must be shorter than the incremental backup frequency"
+            ));
+        }
+
         if let Some(path) = &config.rclone_path {
+            if matches!(backup_target, BackupTarget::Remote(_)) {
+                return Err(anyhow!(
+                    "rclone_path cannot be used when backing up directly to a remote URL"
+                ));
+            }
+
             if !Rclone::is_available().await? {
                 return Err(anyhow!(
                     "rclone is not available but config requests its use"
@@ -212,32 +1460,365 @@ impl BackupConfig {
             }
 
             Rclone::check_path(path).await?;
+
+            if config.check_rclone_write {
+                Rclone::check_write(path).await?;
+            }
+        }
+
+        if config.rclone_transfers == Some(0) {
+            return Err(anyhow!("rclone transfer count must not be zero"));
+        }
+
+        if let Some(bwlimit) = &config.rclone_bwlimit {
+            if bwlimit.trim().is_empty() {
+                return Err(anyhow!("rclone_bwlimit must not be empty"));
+            }
+        }
+
+        let rclone_extra_args = config.rclone_extra_args.unwrap_or_default();
+        for arg in &rclone_extra_args {
+            // Anything else would be taken for the source or destination
+            if !arg.starts_with('-') || arg == "--" {
+                return Err(anyhow!(
+                    "rclone argument `{}` is not a flag, flag values must be given as --flag=value",
+                    arg
+                ));
+            }
+
+            let flag = arg.split('=').next().unwrap_or_default();
+            if Rclone::MANAGED_FLAGS.contains(&flag) {
+                return Err(anyhow!(
+                    "rclone argument `{}` conflicts with arguments set by server-manager",
+                    arg
+                ));
+            }
+        }
+
+        if let Some(template) = &config.remote_path_template {
+            if config.rclone_path.is_none() {
+                return Err(anyhow!(
+                    "remote_path_template requires rclone_path to be set"
+                ));
+            }
+
+            let placeholders_only = ["{year}", "{month}", "{day}", "{hour}"]
+                .iter()
+                .fold(template.clone(), |rest, placeholder| {
+                    rest.replace(placeholder, "")
+                });
+            let valid = !template.is_empty()
+                && !template.starts_with('/')
+                && !template.split('/').any(|part| part == "..")
+                && !placeholders_only.contains(['{', '}']);
+            if !valid {
+                return Err(anyhow!("invalid remote path template `{}`", template));
+            }
+        }
+
+        if config.max_backup_duration_mins == Some(0) {
+            return Err(anyhow!("maximum backup duration must not be zero"));
+        }
+
+        if config.command_timeout_mins == Some(0) {
+            return Err(anyhow!("backup command timeout must not be zero"));
+        }
+
+        if config.backup_warning_secs == Some(0) {
+            return Err(anyhow!("backup warning delay must not be zero"));
+        }
+
+        if matches!(config.backup_nice, Some(nice) if !(-20..=19).contains(&nice)) {
+            return Err(anyhow!("backup niceness must be between -20 and 19"));
+        }
+
+        if matches!(config.backup_ionice_class, Some(class) if class > 3) {
+            return Err(anyhow!(
+                "backup I/O scheduling class must be between 0 and 3"
+            ));
+        }
+
+        if config.duplicity_volsize_mb == Some(0) {
+            return Err(anyhow!("duplicity volume size must not be zero"));
+        }
+
+        let duplicity_extra_args = config.duplicity_extra_args.unwrap_or_default();
+        for arg in &duplicity_extra_args {
+            let flag = arg.split('=').next().unwrap_or_default();
+            let conflicts = Duplicity::MANAGED_FLAGS.contains(&flag)
+                || (flag == "--volsize" && config.duplicity_volsize_mb.is_some());
+
+            if conflicts {
+                return Err(anyhow!(
+                    "duplicity argument `{}` conflicts with arguments set by server-manager",
+                    arg
+                ));
+            }
+        }
+
+        // Patterns are relative to the world folder, and must stay inside it
+        for pattern in &config.backup_excludes {
+            let escapes = Path::new(pattern)
+                .components()
+                .any(|c| !matches!(c, std::path::Component::Normal(_)));
+            if pattern.is_empty() || escapes {
+                return Err(anyhow!(
+                    "invalid backup exclude pattern `{}`, patterns must be relative to the world folder",
+                    pattern
+                ));
+            }
+        }
+
+        match &config.duplicity_encryption {
+            Some(DuplicityEncryption::Passphrase(passphrase)) if passphrase.is_empty() => {
+                return Err(anyhow!("duplicity passphrase must not be empty"));
+            }
+            Some(DuplicityEncryption::GpgAgent(key)) if !Gpg::has_key(key).await? => {
+                return Err(anyhow!("gpg key {} is not in the keyring", key));
+            }
+            _ => (),
+        }
+
+        let mut extra_backup_paths = Vec::with_capacity(config.extra_backup_paths.len());
+        for path in &config.extra_backup_paths {
+            let extra_path = std::fs::canonicalize(server_folder.join(path))
+                .map_err(|_| anyhow!("failed to find extra backup path {}", path))?;
+
+            if !extra_path.starts_with(server_folder) {
+                return Err(anyhow!(
+                    "extra backup path {} is not inside the server folder",
+                    path
+                ));
+            }
+
+            if let BackupTarget::Local(backup_folder) = &backup_target {
+                if extra_path.starts_with(backup_folder) || backup_folder.starts_with(&extra_path) {
+                    return Err(anyhow!(
+                        "extra backup path {} and backup folder {:?} must not be inside one another",
+                        path,
+                        backup_folder
+                    ));
+                }
+            }
+
+            extra_backup_paths.push(extra_path);
+        }
+
+        // Priorities are a nicety, so backups run without them rather than
+        // failing on systems where the tools are missing
+        let mut priority = BackupPriority {
+            nice: config.backup_nice,
+            ionice_class: config.backup_ionice_class,
+        };
+
+        if let Some(nice) = priority.nice {
+            if !Nice::is_available(nice).await {
+                warn!("[BACKUP] nice is not available, backups will run with the default CPU priority.");
+                priority.nice = None;
+            }
+        }
+
+        if let Some(class) = priority.ionice_class {
+            if !Ionice::is_available(class).await {
+                warn!("[BACKUP] ionice is not available, backups will run with the default I/O priority.");
+                priority.ionice_class = None;
+            }
+        }
+
+        if config.confirm_save_off && !config.save_strategy.disables_saving() {
+            return Err(anyhow!(
+                "confirm_save_off requires a save_strategy that disables saving"
+            ));
+        }
+
+        if let Some(mode) = config.snapshot_mode {
+            if !Snapshot::is_available(mode).await? {
+                return Err(anyhow!(
+                    "snapshot tool is not available but config requests its use"
+                ));
+            }
+
+            let world_folder = world_folder
+                .to_str()
+                .ok_or_else(|| anyhow!("world folder path is not valid UTF-8"))?;
+            Snapshot::check_world(mode, world_folder).await?;
         }
 
         Ok(Self {
-            backup_folder,
+            backup_target,
+            server_folder: server_folder.to_path_buf(),
             world_folder,
             incremental: Duration::from_secs(config.incremental_freq_hours * 60 * 60),
+            backup_jitter: Duration::from_secs(config.backup_jitter_secs),
+            initial_backup_delay: config
+                .initial_backup_delay_mins
+                .map(|mins| Duration::from_secs(mins * 60)),
+            catch_up_backups: config.catch_up_backups,
             full_backup_every: config.full_backup_every,
             keep_full_backup: config.keep_full_backup,
             rclone_path: config.rclone_path,
+            rclone_transfers: config.rclone_transfers,
+            rclone_bwlimit: config.rclone_bwlimit,
+            rclone_extra_args,
+            remote_path_template: config.remote_path_template,
+            verify_remote_sync: config.verify_remote_sync,
+            verbose_backup: config.verbose_backup,
+            dry_run: config.dry_run,
             flush_on_save: config.flush_on_save,
             silent: config.silent,
+            snapshot_mode: config.snapshot_mode,
+            save_strategy: config.save_strategy,
+            save_poll_timeout: config.save_poll_timeout_secs.map(Duration::from_secs),
+            save_command: if config.flush_on_save {
+                commands.save_all_flush.clone()
+            } else {
+                commands.save_all.clone()
+            },
+            confirm_save_off: config.confirm_save_off,
+            max_backup_duration: config
+                .max_backup_duration_mins
+                .map(|mins| Duration::from_secs(mins * 60)),
+            command_timeout: config
+                .command_timeout_mins
+                .map(|mins| Duration::from_secs(mins * 60)),
+            backup_warning: config.backup_warning_secs.map(Duration::from_secs),
+            backup_failure_fatal: config.backup_failure_fatal,
+            min_free_space_gb: config.min_free_space_gb,
+            notify_slow_backups: config.notify_slow_backups,
+            verify_region_checksums: config.verify_region_checksums,
+            priority,
+            duplicity_volsize_mb: config.duplicity_volsize_mb,
+            duplicity_extra_args,
+            backup_excludes: config.backup_excludes,
+            duplicity_encryption: config.duplicity_encryption,
+            extra_backup_paths,
         })
     }
+
+    // Credentials are checked here since duplicity only reports missing ones
+    // once it tries to upload. The URL is never included in errors, as it
+    // can contain a key.
+    fn check_remote_target(target: &str) -> Result<String> {
+        let url = Url::parse(target).map_err(|e| anyhow!("invalid backup URL:\n{}", e))?;
+
+        match url.scheme() {
+            "s3" | "s3+http" | "boto3+s3" => {
+                for var in ["AWS_ACCESS_KEY_ID", "AWS_SECRET_ACCESS_KEY"] {
+                    if std::env::var_os(var).is_none() {
+                        return Err(anyhow!(
+                            "backing up to {} requires {} to be set in the environment",
+                            url.scheme(),
+                            var
+                        ));
+                    }
+                }
+            }
+            "b2" => {
+                if url.username().is_empty() || url.password().is_none() {
+                    return Err(anyhow!(
+                        "b2 backup URL must contain credentials, as in b2://account_id:application_key@bucket/path"
+                    ));
+                }
+            }
+            scheme => {
+                return Err(anyhow!(
+                    "unsupported backup URL scheme `{}`, expected s3, s3+http, boto3+s3 or b2",
+                    scheme
+                ));
+            }
+        }
+
+        if url.host_str().unwrap_or_default().is_empty() {
+            return Err(anyhow!("backup URL must name a bucket"));
+        }
+
+        Ok(target.trim_end_matches('/').into())
+    }
 }
 
 #[derive(Clone)]
 pub struct MailConfig {
-    pub smtp_server: String,
+    // None when mails are sent through sendmail
+    pub smtp: Option<SmtpConfig>,
     pub contacts: Mailboxes,
+    // None rather than empty, as an empty Cc header is invalid
+    pub cc: Option<Mailboxes>,
+    pub reply_to: Option<Mailbox>,
     pub sender: Mailbox,
+    pub send_startup_mail: bool,
+    pub strict_mail: bool,
+    pub severities: Vec<NotificationSeverity>,
+    pub templates: MailTemplates,
+}
+
+#[derive(Clone)]
+pub struct MailTemplates {
+    pub startup_subject: String,
+    pub startup_body: String,
+    pub incident_subject: String,
+    pub final_incident_subject: String,
+    pub incident_body: String,
+    pub warning_subject: String,
+    pub warning_body: String,
+}
+
+impl MailTemplates {
+    fn try_from_serialized(templates: MailTemplatesSerialized) -> Result<Self> {
+        let incident_body = templates.incident_body.unwrap_or_else(|| {
+            "On {time}, the Minecraft server \"{name}\" encountered an incident.<br><br>{report}"
+                .into()
+        });
+
+        if !incident_body.contains("{report}") {
+            return Err(anyhow!(
+                "the incident body template must contain {{report}}"
+            ));
+        }
+
+        Ok(Self {
+            startup_subject: templates
+                .startup_subject
+                .unwrap_or_else(|| "{name} - Minecraft Server Manager Started".into()),
+            startup_body: templates.startup_body.unwrap_or_else(|| {
+                "On {time}, the Minecraft server manager for \"{name}\" started.".into()
+            }),
+            incident_subject: templates
+                .incident_subject
+                .unwrap_or_else(|| "{name} - Incident report".into()),
+            final_incident_subject: templates.final_incident_subject.unwrap_or_else(|| {
+                "URGENT - {name} - Server Manager stopped after incident".into()
+            }),
+            incident_body,
+            warning_subject: templates
+                .warning_subject
+                .unwrap_or_else(|| "{name} - Server Manager warning".into()),
+            warning_body: templates.warning_body.unwrap_or_else(|| {
+                "On {time}, the Minecraft server manager for \"{name}\" raised a warning.<br><br>{report}".into()
+            }),
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct SmtpConfig {
+    pub server: String,
     pub credentials: Credentials,
 }
 
 impl MailConfig {
     pub async fn try_from_serialized(config: MailConfigSerialized) -> Result<Self> {
-        let sender = config.sender.parse()?;
+        let sender: Mailbox = config
+            .sender
+            .parse()
+            .map_err(|err| anyhow!("invalid mail sender `{}`: {}", config.sender, err))?;
+
+        let sender = match config.sender_name {
+            Some(name) if name.trim().is_empty() => {
+                return Err(anyhow!("sender_name must not be empty"));
+            }
+            Some(name) => Mailbox::new(Some(name), sender.email),
+            None => sender,
+        };
 
         let mut contacts = Vec::with_capacity(config.contacts.len());
 
@@ -245,13 +1826,488 @@ impl MailConfig {
             contacts.push(c.parse()?);
         }
 
-        let credentials = Credentials::new(config.username, config.password);
+        let mut cc = Vec::with_capacity(config.cc.len());
+
+        for c in config.cc {
+            cc.push(
+                c.parse()
+                    .map_err(|err| anyhow!("invalid cc address `{}`: {}", c, err))?,
+            );
+        }
+
+        let reply_to = match config.reply_to {
+            Some(reply_to) => Some(
+                reply_to
+                    .parse()
+                    .map_err(|err| anyhow!("invalid reply_to address `{}`: {}", reply_to, err))?,
+            ),
+            None => None,
+        };
+
+        let smtp = match config.mail_transport {
+            MailTransport::Smtp => {
+                if config.smtp_server.is_empty() {
+                    return Err(anyhow!("smtp_server is required with the SMTP transport"));
+                }
+
+                Some(SmtpConfig {
+                    server: config.smtp_server,
+                    credentials: Credentials::new(config.username, config.password),
+                })
+            }
+            MailTransport::Sendmail => {
+                if !Sendmail::is_available() {
+                    return Err(anyhow!(
+                        "sendmail is not available but config requests its use"
+                    ));
+                }
+
+                None
+            }
+        };
 
         Ok(Self {
-            smtp_server: config.smtp_server,
+            smtp,
             sender,
             contacts: contacts.into(),
-            credentials,
+            cc: (!cc.is_empty()).then(|| cc.into()),
+            reply_to,
+            send_startup_mail: config.send_startup_mail
+                && config.severities.contains(&NotificationSeverity::Info),
+            strict_mail: config.strict_mail,
+            severities: config.severities,
+            templates: MailTemplates::try_from_serialized(config.mail_templates)?,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct WebhookConfig {
+    pub url: Url,
+    pub method: String,
+    pub content_type: WebhookContentType,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    pub send_startup: bool,
+    pub send_ready: bool,
+    pub severities: Vec<NotificationSeverity>,
+}
+
+impl WebhookConfig {
+    pub async fn try_from_serialized(config: WebhookConfigSerialized) -> Result<Self> {
+        if !Curl::is_available().await? {
+            return Err(anyhow!("curl is not available but config requests its use"));
+        }
+
+        let url =
+            Url::parse(&config.url).map_err(|_| anyhow!("invalid webhook url {}", config.url))?;
+
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Err(anyhow!("webhook url {} must use http or https", url));
+        }
+
+        if config.method.is_empty() || !config.method.chars().all(|c| c.is_ascii_uppercase()) {
+            return Err(anyhow!("invalid webhook method `{}`", config.method));
+        }
+
+        for (name, value) in &config.headers {
+            let valid_name =
+                !name.is_empty() && name.chars().all(|c| c.is_ascii_graphic() && c != ':');
+            if !valid_name || value.contains(['\r', '\n']) {
+                return Err(anyhow!("invalid webhook header `{}`", name));
+            }
+        }
+
+        let body = config.body.unwrap_or_else(|| match config.content_type {
+            WebhookContentType::Json => {
+                r#"{"subject":"{subject}","body":"{body}","severity":"{severity}"}"#.into()
+            }
+            WebhookContentType::Form => "subject={subject}&body={body}&severity={severity}".into(),
+        });
+
+        Ok(Self {
+            url,
+            method: config.method,
+            content_type: config.content_type,
+            headers: config.headers,
+            body,
+            send_startup: config.send_startup
+                && config.severities.contains(&NotificationSeverity::Info),
+            send_ready: config.send_ready
+                && config.severities.contains(&NotificationSeverity::Info),
+            severities: config.severities,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct SlackConfig {
+    pub url: Url,
+    pub channel: Option<String>,
+    pub send_startup: bool,
+    pub send_ready: bool,
+    pub severities: Vec<NotificationSeverity>,
+}
+
+impl SlackConfig {
+    pub async fn try_from_serialized(config: SlackConfigSerialized) -> Result<Self> {
+        if !Curl::is_available().await? {
+            return Err(anyhow!("curl is not available but config requests its use"));
+        }
+
+        let url = Url::parse(&config.url)
+            .map_err(|_| anyhow!("invalid Slack webhook url {}", config.url))?;
+
+        if url.scheme() != "https" {
+            return Err(anyhow!("Slack webhook url {} must use https", url));
+        }
+
+        if let Some(channel) = &config.channel {
+            if channel.is_empty() || channel.contains(char::is_whitespace) {
+                return Err(anyhow!("invalid Slack channel `{}`", channel));
+            }
+        }
+
+        Ok(Self {
+            url,
+            channel: config.channel,
+            send_startup: config.send_startup
+                && config.severities.contains(&NotificationSeverity::Info),
+            send_ready: config.send_ready
+                && config.severities.contains(&NotificationSeverity::Info),
+            severities: config.severities,
         })
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::test_utils::{fake_tools, TempDir};
+
+    // A default config whose server jar, world and backup folders exist in `dir`
+    pub fn config_in(dir: &TempDir) -> ConfigSerialized {
+        fake_tools();
+        dir.write("minecraft_server.jar", "");
+        dir.create_dir("world");
+        dir.create_dir("backups");
+
+        let mut config = ConfigSerialized {
+            server_folder: dir.path().to_str().unwrap().into(),
+            ..Default::default()
+        };
+        backups(&mut config).backup_folder = dir.path().join("backups").to_str().unwrap().into();
+        config
+    }
+
+    // A valid config without backups, for tests of code that needs one
+    pub async fn test_config(dir: &TempDir) -> Config {
+        let config = ConfigSerialized {
+            backups: None,
+            ..config_in(dir)
+        };
+        Config::try_from_serialized(config).await.unwrap()
+    }
+
+    fn backups(config: &mut ConfigSerialized) -> &mut BackupConfigSerialized {
+        config.backups.as_mut().unwrap()
+    }
+
+    async fn error_of(config: ConfigSerialized) -> String {
+        match Config::try_from_serialized(config).await {
+            Ok(_) => panic!("config was accepted"),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[async_std::test]
+    async fn default_config_round_trips() {
+        let dir = TempDir::new();
+        let config = config_in(&dir);
+        let rcon_password = config.rcon_password.clone();
+        let path = dir.path().join("server-manager.ron");
+        config.save(&path).unwrap();
+
+        let config = Config::try_from(&path).await.unwrap();
+        assert_eq!(config.name, "Minecraft Server");
+        assert_eq!(config.server_folder, dir.path());
+        assert_eq!(config.server_jar, dir.path().join("minecraft_server.jar"));
+        assert_eq!(config.working_dir, dir.path());
+        assert_eq!(config.rcon_password, rcon_password);
+        assert_eq!(config.rcon_port, 25575);
+        assert_eq!(config.rcon_host, "localhost");
+        assert_eq!(config.java, PathBuf::from("java"));
+        assert!(config.java_args.is_empty());
+        assert_eq!(config.command_queue_size, default_command_queue_size());
+        assert!(config.shutdown_method == ShutdownMethod::Signal);
+        assert_eq!(config.command_profile.save_all_flush, "save-all flush");
+        assert!(config.idle_shutdown.is_none());
+        assert!(config.health_check.is_none());
+
+        let backups = config.backups.unwrap();
+        assert!(
+            matches!(&backups.backup_target, BackupTarget::Local(path) if path == &dir.path().join("backups"))
+        );
+        assert_eq!(backups.world_folder, dir.path().join("world"));
+        assert_eq!(backups.incremental, Duration::from_secs(60 * 60));
+        assert_eq!(backups.full_backup_every, 24 * 14);
+        assert_eq!(backups.keep_full_backup, 2);
+        assert!(backups.save_strategy == BackupSaveStrategy::SaveOffAndFlush);
+        assert_eq!(backups.save_command, "save-all flush");
+        assert!(backups.duplicity_extra_args.is_empty());
+        assert!(backups.rclone_extra_args.is_empty());
+    }
+
+    #[async_std::test]
+    async fn missing_config_fields_take_their_default() {
+        let dir = TempDir::new();
+        config_in(&dir);
+        let text = format!(
+            r#"(
+                name: "Test",
+                auto_restart: true,
+                server_folder: "{}",
+                server_jar: "minecraft_server.jar",
+                java: "java",
+                java_args: [],
+                rcon_password: "secret",
+                rcon_port: 25576,
+                backups: None,
+            )"#,
+            dir.path().display()
+        );
+
+        let config = Config::try_from_str(&text).await.unwrap();
+        assert_eq!(config.name, "Test");
+        assert_eq!(config.rcon_port, 25576);
+        assert_eq!(config.failure_exit_code, default_failure_exit_code());
+        assert!(config.restart_on_clean_exit);
+        assert!(config.backups.is_none());
+    }
+
+    #[async_std::test]
+    async fn rcon_hosts() {
+        let dir = TempDir::new();
+        for (host, parsed) in [("::1", "::1"), ("[::1]", "::1"), ("mc.local", "mc.local")] {
+            let mut config = config_in(&dir);
+            config.rcon_host = host.into();
+            let config = Config::try_from_serialized(config).await.unwrap();
+            assert_eq!(config.rcon_host, parsed);
+        }
+
+        for host in ["", "mc.local:25575", "[mc.local]", "my host"] {
+            let mut config = config_in(&dir);
+            config.rcon_host = host.into();
+            assert_eq!(
+                error_of(config).await,
+                format!("invalid RCON host `{}`", host)
+            );
+        }
+    }
+
+    #[async_std::test]
+    async fn rejects_missing_server_folder() {
+        let dir = TempDir::new();
+        let mut config = config_in(&dir);
+        config.server_folder = dir.path().join("missing").to_str().unwrap().into();
+        assert!(error_of(config)
+            .await
+            .starts_with("failed to find server folder"));
+    }
+
+    #[async_std::test]
+    async fn rejects_missing_server_jar() {
+        let dir = TempDir::new();
+        let mut config = config_in(&dir);
+        config.server_jar = "missing.jar".into();
+        assert!(error_of(config)
+            .await
+            .starts_with("failed to find server jar"));
+    }
+
+    #[async_std::test]
+    async fn rejects_missing_backup_folder() {
+        let dir = TempDir::new();
+        let mut config = config_in(&dir);
+        backups(&mut config).backup_folder = dir.path().join("missing").to_str().unwrap().into();
+        assert!(error_of(config)
+            .await
+            .starts_with("failed to find backup folder"));
+    }
+
+    #[async_std::test]
+    async fn rejects_backup_folder_inside_world_folder() {
+        let dir = TempDir::new();
+        let mut config = config_in(&dir);
+        let inside = dir.create_dir("world/backups");
+        backups(&mut config).backup_folder = inside.to_str().unwrap().into();
+        assert!(error_of(config)
+            .await
+            .contains("must not be inside one another"));
+    }
+
+    #[async_std::test]
+    async fn rejects_world_folder_inside_backup_folder() {
+        let dir = TempDir::new();
+        let mut config = config_in(&dir);
+        // Also checked for a world that was not generated yet
+        backups(&mut config).world_folder = "backups/world".into();
+        assert!(error_of(config)
+            .await
+            .contains("must not be inside one another"));
+    }
+
+    #[async_std::test]
+    async fn rejects_zero_incremental_frequency() {
+        let dir = TempDir::new();
+        let mut config = config_in(&dir);
+        backups(&mut config).incremental_freq_hours = 0;
+        assert_eq!(
+            error_of(config).await,
+            "incremental backup frequency must not be zero"
+        );
+    }
+
+    #[async_std::test]
+    async fn rejects_unknown_timezones() {
+        let dir = TempDir::new();
+        for timezone in ["", "Nowhere/Atlantis", "../zoneinfo/UTC", "Europe/"] {
+            let mut config = config_in(&dir);
+            config.timezone = Some(timezone.into());
+            assert_eq!(
+                error_of(config).await,
+                format!("unknown time zone {}", timezone)
+            );
+        }
+    }
+
+    #[async_std::test]
+    async fn rejects_managed_duplicity_flags() {
+        let dir = TempDir::new();
+        for arg in ["--no-encryption", "--full-if-older-than=1D", "--exclude=**"] {
+            let mut config = config_in(&dir);
+            backups(&mut config).duplicity_extra_args = Some(vec![arg.into()]);
+            assert_eq!(
+                error_of(config).await,
+                format!(
+                    "duplicity argument `{}` conflicts with arguments set by server-manager",
+                    arg
+                )
+            );
+        }
+    }
+
+    #[async_std::test]
+    async fn rejects_volsize_flag_only_with_duplicity_volsize_mb() {
+        let dir = TempDir::new();
+        let mut config = config_in(&dir);
+        backups(&mut config).duplicity_extra_args = Some(vec!["--volsize=50".into()]);
+        assert!(Config::try_from_serialized(config).await.is_ok());
+
+        let mut config = config_in(&dir);
+        backups(&mut config).duplicity_extra_args = Some(vec!["--volsize=50".into()]);
+        backups(&mut config).duplicity_volsize_mb = Some(100);
+        assert!(error_of(config).await.contains("conflicts with arguments"));
+    }
+
+    #[async_std::test]
+    async fn rejects_managed_rclone_flags() {
+        let dir = TempDir::new();
+        for arg in ["--transfers=8", "--bwlimit=1M", "--stats"] {
+            let mut config = config_in(&dir);
+            backups(&mut config).rclone_extra_args = Some(vec![arg.into()]);
+            assert_eq!(
+                error_of(config).await,
+                format!(
+                    "rclone argument `{}` conflicts with arguments set by server-manager",
+                    arg
+                )
+            );
+        }
+    }
+
+    #[async_std::test]
+    async fn rejects_rclone_positionals() {
+        let dir = TempDir::new();
+        for arg in ["remote:other", "--"] {
+            let mut config = config_in(&dir);
+            backups(&mut config).rclone_extra_args = Some(vec![arg.into()]);
+            assert!(error_of(config).await.contains("is not a flag"));
+        }
+    }
+
+    #[async_std::test]
+    async fn rejects_confirm_save_off_without_disabling_saves() {
+        let dir = TempDir::new();
+        for strategy in [BackupSaveStrategy::FlushOnly, BackupSaveStrategy::None] {
+            let mut config = config_in(&dir);
+            backups(&mut config).confirm_save_off = true;
+            backups(&mut config).save_strategy = strategy;
+            assert_eq!(
+                error_of(config).await,
+                "confirm_save_off requires a save_strategy that disables saving"
+            );
+        }
+
+        let mut config = config_in(&dir);
+        backups(&mut config).confirm_save_off = true;
+        backups(&mut config).save_strategy = BackupSaveStrategy::SaveOffOnly;
+        assert!(Config::try_from_serialized(config).await.is_ok());
+    }
+
+    #[async_std::test]
+    async fn rejects_zero_values() {
+        let dir = TempDir::new();
+        type Change = fn(&mut ConfigSerialized);
+        let cases: [(Change, &str); 5] = [
+            (
+                |config| config.command_queue_size = 0,
+                "command queue size must not be zero",
+            ),
+            (
+                |config| config.failure_exit_code = 0,
+                "failure_exit_code must not be zero",
+            ),
+            (
+                |config| config.idle_shutdown_mins = Some(0),
+                "idle shutdown delay must not be zero",
+            ),
+            (
+                |config| backups(config).max_backup_duration_mins = Some(0),
+                "maximum backup duration must not be zero",
+            ),
+            (
+                |config| backups(config).duplicity_volsize_mb = Some(0),
+                "duplicity volume size must not be zero",
+            ),
+        ];
+
+        for (change, error) in cases {
+            let mut config = config_in(&dir);
+            change(&mut config);
+            assert_eq!(error_of(config).await, error);
+        }
+    }
+
+    #[async_std::test]
+    async fn rejects_empty_commands() {
+        let dir = TempDir::new();
+        let mut config = config_in(&dir);
+        config.command_profile.stop = " ".into();
+        assert_eq!(error_of(config).await, "stop command must not be empty");
+    }
+
+    #[async_std::test]
+    async fn rejects_regex_ready_patterns() {
+        let dir = TempDir::new();
+        for pattern in ["*", "Done \\(.*\\)!", "Done (.*)!"] {
+            let mut config = config_in(&dir);
+            config.ready_pattern = Some(pattern.into());
+            assert!(error_of(config).await.starts_with("ready pattern"));
+        }
+
+        let mut config = config_in(&dir);
+        config.ready_pattern = Some("Done (*)! For help".into());
+        assert!(Config::try_from_serialized(config).await.is_ok());
+    }
+}
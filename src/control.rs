@@ -0,0 +1,363 @@
+use std::{
+    fs::Permissions,
+    io::ErrorKind,
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use async_std::{
+    channel::{self, Sender, TrySendError},
+    io::{prelude::BufReadExt, BufReader, ReadExt, WriteExt},
+    os::unix::net::{UnixListener, UnixStream},
+    prelude::FutureExt as AsyncStdFutureExt,
+    stream::StreamExt,
+};
+use chrono::{DateTime, Utc};
+
+use crate::{
+    backup::BackupRequest,
+    config::CommandProfile,
+    rcon::{json_string, MinecraftCommand},
+    server::request_stop,
+};
+
+// Kept in the working directory, like the default config
+pub const SOCKET_NAME: &str = "server-manager.sock";
+
+const MAX_COMMAND_LEN: u64 = 1024;
+
+// Short enough for the three world queries to fit in the time allowed for a
+// command
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Default)]
+pub struct ManagerStatus {
+    pub server_pid: Option<u32>,
+    pub server_started: Option<DateTime<Utc>>,
+    // Only known when a ready pattern is configured
+    pub server_ready: bool,
+    pub backup_running: bool,
+    pub last_backup: Option<DateTime<Utc>>,
+    // When the next scheduled backup is due, even if backups are paused
+    pub next_backup: Option<DateTime<Utc>>,
+    pub backups_paused: bool,
+    // Pauses without an end last until backups are resumed
+    pub backups_resume_at: Option<DateTime<Utc>>,
+    // Counted since the manager started
+    pub backup_failures: u64,
+    pub sync_failures_recoverable: u64,
+    pub sync_failures_fatal: u64,
+    // Commands to the running server, to query details of its world
+    pub server_commands: Option<Sender<MinecraftCommand>>,
+    // Kept until the server stops, as it does not change
+    pub seed: Option<String>,
+}
+
+impl ManagerStatus {
+    pub fn backups_paused(&self) -> bool {
+        self.backups_paused && self.backups_resume_at.is_none_or(|time| time > Utc::now())
+    }
+}
+
+pub type SharedStatus = Arc<Mutex<ManagerStatus>>;
+
+// Details of the world of the running server, left out when it cannot be
+// queried, such as when the commands are disabled or not Minecraft's
+#[derive(Default)]
+struct WorldStatus {
+    seed: Option<String>,
+    difficulty: Option<String>,
+    daytime: Option<u64>,
+}
+
+pub struct ControlServer;
+
+impl ControlServer {
+    // Returns None if another manager already listens on the socket.
+    pub async fn bind() -> Result<Option<UnixListener>> {
+        let path = Path::new(SOCKET_NAME);
+        if path.exists() {
+            if UnixStream::connect(path).await.is_ok() {
+                return Ok(None);
+            }
+
+            // Left over by a manager that did not exit cleanly
+            std::fs::remove_file(path)?;
+        }
+
+        let listener = UnixListener::bind(path).await?;
+        std::fs::set_permissions(path, Permissions::from_mode(0o600))?;
+        Ok(Some(listener))
+    }
+
+    // Answers line commands with a single line of JSON.
+    // The backup trigger is None when backups are not configured.
+    pub async fn serve(
+        listener: UnixListener,
+        name: String,
+        commands: CommandProfile,
+        status: SharedStatus,
+        backup_trigger: Option<Sender<BackupRequest>>,
+    ) {
+        let mut incoming = listener.incoming();
+        while let Some(stream) = incoming.next().await {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    warn!("[CONTROL] Failed to accept connection:\n{}", err);
+                    continue;
+                }
+            };
+
+            let name = name.clone();
+            let commands = commands.clone();
+            let status = status.clone();
+            let backup_trigger = backup_trigger.clone();
+            async_std::task::spawn(async move {
+                Self::handle(stream, &name, &commands, &status, backup_trigger.as_ref())
+                    .timeout(Duration::from_secs(10))
+                    .await
+                    .ok();
+            });
+        }
+    }
+
+    async fn handle(
+        stream: UnixStream,
+        name: &str,
+        commands: &CommandProfile,
+        status: &SharedStatus,
+        backup_trigger: Option<&Sender<BackupRequest>>,
+    ) -> Result<()> {
+        let mut command = String::new();
+        BufReader::new((&stream).take(MAX_COMMAND_LEN))
+            .read_line(&mut command)
+            .await?;
+        let command = command.trim();
+        debug!("[CONTROL] Received command `{}`.", command);
+
+        let response = match command {
+            "status" => Self::status(name, commands, status, backup_trigger.is_some()).await,
+            "backup now" => Self::request_backup(backup_trigger, BackupRequest::Now),
+            "backup full" => Self::request_backup(backup_trigger, BackupRequest::Full),
+            "backup resume" => Self::pause_backups(status, backup_trigger.is_some(), None),
+            _ if command == "backup pause" || command.starts_with("backup pause ") => {
+                let duration = command["backup pause".len()..].trim();
+                Self::pause_backups(status, backup_trigger.is_some(), Some(duration))
+            }
+            "stop" => "{\"ok\":true}".into(),
+            _ => error_response("unknown command"),
+        };
+
+        (&stream)
+            .write_all(format!("{}\n", response).as_bytes())
+            .await?;
+
+        if command == "stop" {
+            info!("[CONTROL] Stop requested.");
+            request_stop();
+        }
+
+        Ok(())
+    }
+
+    fn request_backup(
+        backup_trigger: Option<&Sender<BackupRequest>>,
+        request: BackupRequest,
+    ) -> String {
+        match backup_trigger.map(|trigger| trigger.try_send(request)) {
+            None => error_response("backups are not configured"),
+            Some(Ok(())) => {
+                match request {
+                    BackupRequest::Now => info!("[CONTROL] Backup requested."),
+                    BackupRequest::Full => info!("[CONTROL] Full backup requested."),
+                }
+                "{\"ok\":true}".into()
+            }
+            Some(Err(TrySendError::Full(_))) => error_response("a backup is already pending"),
+            Some(Err(TrySendError::Closed(_))) => error_response("backups are not running"),
+        }
+    }
+
+    // Resumes backups if `duration` is None. Otherwise, an empty duration
+    // pauses them until they are resumed, and a number of minutes pauses them
+    // for that long.
+    fn pause_backups(
+        status: &SharedStatus,
+        backups_enabled: bool,
+        duration: Option<&str>,
+    ) -> String {
+        if !backups_enabled {
+            return error_response("backups are not configured");
+        }
+
+        let resume_at = match duration {
+            None | Some("") => None,
+            Some(minutes) => match minutes.parse::<u32>() {
+                Ok(minutes) if minutes > 0 => {
+                    Some(Utc::now() + chrono::Duration::minutes(minutes.into()))
+                }
+                _ => return error_response("invalid pause duration"),
+            },
+        };
+
+        let mut status = match status.lock() {
+            Ok(status) => status,
+            Err(_) => return error_response("failed to update status"),
+        };
+
+        status.backups_paused = duration.is_some();
+        status.backups_resume_at = resume_at;
+
+        match (duration, resume_at) {
+            (None, _) => info!("[CONTROL] Backups resumed."),
+            (Some(_), None) => info!("[CONTROL] Backups paused."),
+            (Some(_), Some(time)) => info!("[CONTROL] Backups paused until {}.", time),
+        }
+
+        "{\"ok\":true}".into()
+    }
+
+    async fn status(
+        name: &str,
+        commands: &CommandProfile,
+        status: &SharedStatus,
+        backups_enabled: bool,
+    ) -> String {
+        let world = Self::query_world(commands, status).await;
+
+        let status = match status.lock() {
+            Ok(status) => status,
+            Err(_) => return error_response("failed to read status"),
+        };
+
+        let time = |time: Option<DateTime<Utc>>| match time {
+            Some(time) => json_string(&time.to_rfc3339()),
+            None => "null".into(),
+        };
+        let text = |text: &Option<String>| match text {
+            Some(text) => json_string(text),
+            None => "null".into(),
+        };
+
+        format!(
+            "{{\"ok\":true,\"running\":true,\"name\":{},\"server\":{{\"running\":{},\"pid\":{},\"started\":{},\"ready\":{}}},\"world\":{{\"seed\":{},\"difficulty\":{},\"daytime\":{}}},\"backup\":{{\"enabled\":{},\"running\":{},\"last\":{},\"next\":{},\"paused\":{},\"resume_at\":{},\"failures\":{},\"sync_failures\":{{\"recoverable\":{},\"fatal\":{}}}}}}}",
+            json_string(name),
+            status.server_pid.is_some(),
+            status
+                .server_pid
+                .map_or_else(|| "null".into(), |pid| pid.to_string()),
+            time(status.server_started),
+            status.server_ready,
+            text(&world.seed),
+            text(&world.difficulty),
+            world
+                .daytime
+                .map_or_else(|| "null".into(), |daytime| daytime.to_string()),
+            backups_enabled,
+            status.backup_running,
+            time(status.last_backup),
+            time(status.next_backup),
+            status.backups_paused(),
+            time(status.backups_resume_at.filter(|_| status.backups_paused())),
+            status.backup_failures,
+            status.sync_failures_recoverable,
+            status.sync_failures_fatal,
+        )
+    }
+
+    // The seed is only queried until it is known. Queries stop at the first
+    // one left unanswered, as the server is then unlikely to answer the others.
+    async fn query_world(commands: &CommandProfile, status: &SharedStatus) -> WorldStatus {
+        let (cmd_chan, seed) = match status.lock() {
+            Ok(status) => (status.server_commands.clone(), status.seed.clone()),
+            Err(_) => return WorldStatus::default(),
+        };
+        let cmd_chan = match cmd_chan {
+            Some(cmd_chan) => cmd_chan,
+            None => return WorldStatus::default(),
+        };
+
+        let mut world = WorldStatus {
+            seed,
+            ..Default::default()
+        };
+
+        if world.seed.is_none() {
+            let response = match query(&cmd_chan, &commands.seed).await {
+                Some(response) => response,
+                None => return world,
+            };
+            world.seed = parse_seed(&response);
+            if let (Some(seed), Ok(mut status)) = (&world.seed, status.lock()) {
+                status.seed = Some(seed.clone());
+            }
+        }
+
+        let response = match query(&cmd_chan, &commands.difficulty).await {
+            Some(response) => response,
+            None => return world,
+        };
+        world.difficulty = response
+            .trim()
+            .strip_prefix("The difficulty is ")
+            .map(str::to_lowercase);
+
+        if let Some(response) = query(&cmd_chan, &commands.time_query).await {
+            world.daytime = response
+                .trim()
+                .strip_prefix("The time is ")
+                .and_then(|time| time.parse().ok());
+        }
+
+        world
+    }
+}
+
+// Sends a command to the running server without waiting on a full command
+// queue, and returns its response if it comes in time.
+async fn query(cmd_chan: &Sender<MinecraftCommand>, command: &str) -> Option<String> {
+    let (resp_send, resp_rec) = channel::bounded(1);
+    cmd_chan
+        .try_send(MinecraftCommand::Raw(command.into(), resp_send))
+        .ok()?;
+    resp_rec.recv().timeout(QUERY_TIMEOUT).await.ok()?.ok()
+}
+
+// Minecraft answers `Seed: [-1234]`
+fn parse_seed(response: &str) -> Option<String> {
+    let seed = response.trim().strip_prefix("Seed: [")?.strip_suffix(']')?;
+    seed.parse::<i64>().ok().map(|seed| seed.to_string())
+}
+
+fn error_response(error: &str) -> String {
+    format!("{{\"ok\":false,\"error\":{}}}", json_string(error))
+}
+
+// Sends a command to the manager running in the working directory and returns
+// its response, or None if no manager is running.
+pub async fn request(command: &str) -> Result<Option<String>> {
+    let stream = match UnixStream::connect(SOCKET_NAME).await {
+        Ok(stream) => stream,
+        Err(err)
+            if matches!(
+                err.kind(),
+                ErrorKind::NotFound | ErrorKind::ConnectionRefused
+            ) =>
+        {
+            return Ok(None);
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    (&stream)
+        .write_all(format!("{}\n", command).as_bytes())
+        .await?;
+
+    let mut response = String::new();
+    BufReader::new(&stream).read_line(&mut response).await?;
+    Ok(Some(response.trim_end().into()))
+}
@@ -0,0 +1,116 @@
+use std::{
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use async_std::{
+    channel::{self, Receiver, Sender},
+    prelude::FutureExt as AsyncStdFutureExt,
+};
+
+use crate::{
+    config::{CommandProfile, DrainConfig},
+    idle::parse_player_count,
+    properties::Properties,
+    rcon::MinecraftCommand,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+// Set while the whitelist is only enabled because of a drain
+static WHITELIST_ENABLED: AtomicBool = AtomicBool::new(false);
+
+// Waits for players to leave before a planned restart, up to the configured
+// timeout, optionally enabling the whitelist so that no one else joins.
+pub async fn drain(
+    config: &DrainConfig,
+    server_folder: &Path,
+    commands: &CommandProfile,
+    cmd_chan: &Sender<MinecraftCommand>,
+) {
+    let msg = format!(
+        "Server restarting in at most {} minutes, please log off when you can.",
+        config.timeout.as_secs() / 60
+    );
+    cmd_chan
+        .send(MinecraftCommand::Broadcast(msg))
+        .timeout(Duration::from_secs(10))
+        .await
+        .ok();
+
+    let (resp_send, resp_rec) = channel::bounded(1);
+
+    // An already enabled whitelist is left as it is after the restart
+    let whitelisted = Properties::load(&server_folder.join("server.properties"))
+        .is_ok_and(|properties| properties.get("white-list") == Some("true"));
+    if config.whitelist && !whitelisted {
+        match run(cmd_chan, "whitelist on", &resp_send, &resp_rec).await {
+            Some(_) => {
+                info!("[DRAIN] Whitelist enabled until the server restarts.");
+                WHITELIST_ENABLED.store(true, Ordering::SeqCst);
+            }
+            None => warn!("[DRAIN] Failed to enable the whitelist."),
+        }
+    }
+
+    info!("[DRAIN] Waiting for players to leave...");
+    let deadline = Instant::now() + config.timeout;
+    loop {
+        let players = run(cmd_chan, &commands.list, &resp_send, &resp_rec)
+            .await
+            .and_then(|response| parse_player_count(&response));
+
+        if players == Some(0) {
+            info!("[DRAIN] All players left.");
+            return;
+        }
+
+        if Instant::now() >= deadline {
+            info!("[DRAIN] Players are still online, restarting anyway.");
+            return;
+        }
+
+        async_std::task::sleep(
+            POLL_INTERVAL.min(deadline.saturating_duration_since(Instant::now())),
+        )
+        .await;
+    }
+}
+
+// Disables the whitelist again in server.properties if a drain enabled it, as
+// the server saves it there. Called once the server stopped.
+pub fn restore_whitelist(server_folder: &Path) {
+    if !WHITELIST_ENABLED.swap(false, Ordering::SeqCst) {
+        return;
+    }
+
+    let path = server_folder.join("server.properties");
+    match Properties::update(&path, &[("white-list", "false")]) {
+        Ok(()) => info!("[DRAIN] Whitelist disabled again."),
+        Err(err) => error!("[DRAIN] Failed to disable the whitelist again:\n{}", err),
+    }
+}
+
+async fn run(
+    cmd_chan: &Sender<MinecraftCommand>,
+    command: &str,
+    resp_send: &Sender<String>,
+    resp_rec: &Receiver<String>,
+) -> Option<String> {
+    while resp_rec.try_recv().is_ok() {}
+
+    cmd_chan
+        .send(MinecraftCommand::Raw(command.into(), resp_send.clone()))
+        .timeout(Duration::from_secs(10))
+        .await
+        .ok()?
+        .ok()?;
+
+    resp_rec
+        .recv()
+        .timeout(Duration::from_secs(30))
+        .await
+        .ok()?
+        .ok()
+}
@@ -0,0 +1,110 @@
+use std::time::{Duration, Instant};
+
+use async_std::{
+    channel::{self, Sender},
+    future::pending,
+    prelude::FutureExt as AsyncStdFutureExt,
+};
+
+use crate::{config::CommandProfile, rcon::MinecraftCommand};
+
+const GRACE_PERIOD: Duration = Duration::from_secs(5 * 60);
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct IdleManager;
+
+impl IdleManager {
+    // Resolves once the server has been empty for `idle_timeout`, after asking it
+    // to stop through RCON if `rcon_stop` is set.
+    pub async fn start(
+        idle_timeout: Option<Duration>,
+        rcon_stop: bool,
+        commands: CommandProfile,
+        cmd_chan: Sender<MinecraftCommand>,
+    ) {
+        let idle_timeout = match idle_timeout {
+            Some(timeout) => timeout,
+            None => pending().await,
+        };
+
+        let (resp_send, resp_rec) = channel::bounded(1);
+
+        // Let the server finish starting up before counting it as idle
+        async_std::task::sleep(GRACE_PERIOD).await;
+
+        let mut idle_since = Instant::now();
+        loop {
+            async_std::task::sleep(POLL_INTERVAL).await;
+
+            while resp_rec.try_recv().is_ok() {}
+
+            if cmd_chan
+                .send(MinecraftCommand::Raw(
+                    commands.list.clone(),
+                    resp_send.clone(),
+                ))
+                .timeout(Duration::from_secs(10))
+                .await
+                .is_err()
+            {
+                continue;
+            }
+
+            // Player count is unknown while RCON is unavailable, so do not count it as idle
+            let players = match resp_rec.recv().timeout(Duration::from_secs(30)).await {
+                Ok(Ok(response)) => parse_player_count(&response),
+                _ => None,
+            };
+
+            match players {
+                Some(count) => debug!("[IDLE] {} players online.", count),
+                None => debug!("[IDLE] Failed to get player count."),
+            }
+
+            match players {
+                Some(0) if Instant::now() - idle_since >= idle_timeout => {
+                    info!("[IDLE] No players online for too long, stopping server.");
+                    if !rcon_stop {
+                        return;
+                    }
+
+                    // Wait for the server to acknowledge so the command is not lost
+                    while resp_rec.try_recv().is_ok() {}
+                    if cmd_chan
+                        .send(MinecraftCommand::Raw(
+                            commands.stop.clone(),
+                            resp_send.clone(),
+                        ))
+                        .timeout(Duration::from_secs(10))
+                        .await
+                        .is_ok()
+                    {
+                        resp_rec.recv().timeout(Duration::from_secs(30)).await.ok();
+                    }
+                    return;
+                }
+                Some(0) | None => (),
+                Some(_) => idle_since = Instant::now(),
+            }
+        }
+    }
+}
+
+pub fn parse_player_count(response: &str) -> Option<u32> {
+    let mut plain = String::with_capacity(response.len());
+    let mut chars = response.chars();
+    while let Some(c) = chars.next() {
+        // Skip formatting codes such as `§6`
+        if c == '§' {
+            chars.next();
+        } else {
+            plain.push(c);
+        }
+    }
+
+    plain
+        .split(|c: char| !c.is_ascii_digit())
+        .find(|s| !s.is_empty())?
+        .parse()
+        .ok()
+}
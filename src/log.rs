@@ -0,0 +1,77 @@
+use std::sync::{
+    atomic::{AtomicBool, AtomicU8, Ordering},
+    OnceLock,
+};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum LogLevel {
+    Debug,
+    #[default]
+    Info,
+    Warn,
+    Error,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+// Set from the command line, takes precedence over the configured level
+static QUIET: AtomicBool = AtomicBool::new(false);
+// Tells servers apart once the config is loaded, such as `[Survival]`
+static NAME_TAG: OnceLock<String> = OnceLock::new();
+
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn set_quiet() {
+    QUIET.store(true, Ordering::Relaxed);
+}
+
+// Only the first name is kept, an empty name adds no tag
+pub fn set_name(name: &str) {
+    if !name.is_empty() {
+        NAME_TAG.set(format!("[{}]", name)).ok();
+    }
+}
+
+pub fn name_tag() -> &'static str {
+    NAME_TAG.get().map_or("", String::as_str)
+}
+
+pub fn enabled(level: LogLevel) -> bool {
+    let min = if QUIET.load(Ordering::Relaxed) {
+        LEVEL.load(Ordering::Relaxed).max(LogLevel::Warn as u8)
+    } else {
+        LEVEL.load(Ordering::Relaxed)
+    };
+    level as u8 >= min
+}
+
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::log::enabled($level) {
+            println!(
+                "[ServerManager]{} {}",
+                $crate::log::name_tag(),
+                format_args!($($arg)*)
+            );
+        }
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => { log!($crate::log::LogLevel::Debug, $($arg)*) };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => { log!($crate::log::LogLevel::Info, $($arg)*) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => { log!($crate::log::LogLevel::Warn, $($arg)*) };
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => { log!($crate::log::LogLevel::Error, $($arg)*) };
+}
@@ -1,133 +1,391 @@
 use std::time::Duration;
 
-use anyhow::Result;
-use async_std::channel::{Receiver, TryRecvError};
-use chrono::{DateTime, Utc};
+use anyhow::{anyhow, Result};
+use async_std::channel::{Receiver, Sender};
+use chrono::{DateTime, Local, Utc};
 use lettre::{
-    message::header::{ContentType, To},
-    AsyncSmtpTransport, AsyncStd1Executor, AsyncTransport, Message,
+    message::{
+        header::{Cc, ContentType, To},
+        Attachment, MessageBuilder, MultiPart, SinglePart,
+    },
+    AsyncSendmailTransport, AsyncSmtpTransport, AsyncStd1Executor, AsyncTransport, Message,
 };
 
-use crate::config::MailConfig;
+use crate::{
+    cmd_utils::Gzip,
+    config::{MailConfig, NotificationSeverity},
+    webhook::fill_template,
+};
 
 pub struct MailRequest {
     pub err_log: Vec<String>,
     pub final_incident: bool,
+    // Warnings hold a message in `err_log` instead of an error report
+    pub warning: bool,
     pub time: DateTime<Utc>,
 }
 
+// The log tag of a notifier, its channel and the severities it handles
+pub type ReportChannel = (&'static str, Sender<MailRequest>, Vec<NotificationSeverity>);
+
+pub async fn send_report(
+    chans: &[ReportChannel],
+    err_log: Vec<String>,
+    final_incident: bool,
+    time: DateTime<Utc>,
+) {
+    let severity = NotificationSeverity::of_report(final_incident);
+    send_request(chans, severity, err_log, final_incident, time).await;
+}
+
+pub async fn send_warning(chans: &[ReportChannel], message: String, time: DateTime<Utc>) {
+    send_request(
+        chans,
+        NotificationSeverity::Warning,
+        vec![message],
+        false,
+        time,
+    )
+    .await;
+}
+
+async fn send_request(
+    chans: &[ReportChannel],
+    severity: NotificationSeverity,
+    err_log: Vec<String>,
+    final_incident: bool,
+    time: DateTime<Utc>,
+) {
+    for (tag, chan, _) in chans.iter().filter(|(_, _, s)| s.contains(&severity)) {
+        let request = MailRequest {
+            err_log: err_log.clone(),
+            final_incident,
+            warning: severity == NotificationSeverity::Warning,
+            time,
+        };
+
+        // The report is logged instead, so that it is not lost entirely
+        if chan.send(request).await.is_err() {
+            error!(
+                "[{}] Notification task stopped, failed to send report:\n{}",
+                tag,
+                err_log.join("\n")
+            );
+        }
+    }
+}
+
+// Longer error logs only show their first and last lines in the mail body
+const MAX_INLINE_LINES: usize = 40;
+const MAX_INLINE_LINE_LEN: usize = 500;
+
+// Larger attachments are compressed to fit within the size limits of providers
+const COMPRESS_ATTACHMENT_BYTES: usize = 256 * 1024;
+
 pub struct MailManager;
 
 impl MailManager {
-    pub async fn test_mail(config: MailConfig, name: &str) -> Result<()> {
-        let email = Message::builder()
-            .from(config.sender)
-            .mailbox::<To>(config.contacts.into())
+    pub async fn test_mail(config: MailConfig, name: &str, local_time: bool) -> Result<()> {
+        let mut attempts = 0;
+        loop {
+            match Self::try_test_mail(config.clone(), name, local_time).await {
+                Ok(()) => return Ok(()),
+                Err(err) if attempts >= 4 => return Err(err),
+                Err(err) => {
+                    let backoff = Duration::from_secs(2u64.pow(attempts + 1));
+                    warn!(
+                        "[MAIL] Mail check failed, retrying in {} seconds:\n{}",
+                        backoff.as_secs(),
+                        err
+                    );
+                    async_std::task::sleep(backoff).await;
+                    attempts += 1;
+                }
+            }
+        }
+    }
+
+    async fn try_test_mail(config: MailConfig, name: &str, local_time: bool) -> Result<()> {
+        if let Some(smtp) = &config.smtp {
+            let transport = AsyncSmtpTransport::<AsyncStd1Executor>::relay(&smtp.server)?
+                .credentials(smtp.credentials.clone())
+                .build::<AsyncStd1Executor>();
+
+            if !transport.test_connection().await? {
+                return Err(anyhow!("failed to connect to SMTP server {}", smtp.server));
+            }
+        }
+
+        if !config.send_startup_mail {
+            return Ok(());
+        }
+
+        let values = template_values(name, format_time(Utc::now(), local_time));
+
+        let email = Self::builder(&config)
             .header(ContentType::TEXT_HTML)
-            .subject(format!("{} - Minecraft Server Manager Started", name))
-            .body(format!(
-                "On {}, the Minecraft server manager for \"{}\" started.",
-                Utc::now(),
-                name
-            ))?;
-
-        AsyncSmtpTransport::<AsyncStd1Executor>::relay(&config.smtp_server)?
-            .credentials(config.credentials)
-            .build()
-            .send(email)
-            .await
-            .map(drop)
-            .map_err(Into::into)
+            .subject(fill_template(&config.templates.startup_subject, &values))
+            .body(fill_template(&config.templates.startup_body, &values))?;
+
+        Self::send(&config, email).await
+    }
+
+    fn builder(config: &MailConfig) -> MessageBuilder {
+        let mut builder = Message::builder()
+            .from(config.sender.clone())
+            .mailbox::<To>(config.contacts.clone().into());
+
+        if let Some(reply_to) = &config.reply_to {
+            builder = builder.reply_to(reply_to.clone());
+        }
+
+        if let Some(cc) = &config.cc {
+            builder = builder.mailbox::<Cc>(cc.clone().into());
+        }
+
+        builder
+    }
+
+    async fn send(config: &MailConfig, email: Message) -> Result<()> {
+        match &config.smtp {
+            Some(smtp) => AsyncSmtpTransport::<AsyncStd1Executor>::relay(&smtp.server)?
+                .credentials(smtp.credentials.clone())
+                .build()
+                .send(email)
+                .await
+                .map(drop)
+                .map_err(Into::into),
+            None => AsyncSendmailTransport::<AsyncStd1Executor>::new()
+                .send(email)
+                .await
+                .map(drop)
+                .map_err(Into::into),
+        }
     }
 
     pub async fn start(
         config: MailConfig,
         name: String,
+        local_time: bool,
         mail_rec: Receiver<MailRequest>,
     ) -> Result<()> {
         let mut mail_requests = Vec::new();
         loop {
             mail_requests.clear();
-            mail_requests.push(mail_rec.recv().await?);
+            match mail_rec.recv().await {
+                Ok(mail) => mail_requests.push(mail),
+                Err(_) => break,
+            }
 
             loop {
                 async_std::task::sleep(Duration::from_secs(30)).await;
 
+                // The channel is also closed when the manager exits, in which
+                // case the reports received so far are still sent
                 match mail_rec.try_recv() {
                     Ok(mail) => mail_requests.push(mail),
-                    Err(TryRecvError::Empty) => break,
-                    Err(x) => return Err(x.into()),
+                    Err(_) => break,
                 }
 
-                loop {
-                    match mail_rec.try_recv() {
-                        Ok(mail) => mail_requests.push(mail),
-                        Err(TryRecvError::Empty) => break,
-                        Err(x) => return Err(x.into()),
-                    }
+                while let Ok(mail) = mail_rec.try_recv() {
+                    mail_requests.push(mail);
                 }
             }
 
-            let is_final = mail_requests.iter().any(|x| x.final_incident);
+            // Warnings are mailed on their own, so that they stand out from the
+            // incident reports they follow
+            let (warnings, incidents): (Vec<_>, Vec<_>) =
+                mail_requests.drain(..).partition(|x| x.warning);
+            let is_final = incidents.iter().any(|x| x.final_incident);
 
-            let subject = if is_final {
-                format!("URGENT - {} - Server Manager stopped after incident", name)
-            } else {
-                format!("{} - Incident report", name)
-            };
+            if !incidents.is_empty() {
+                let email = Self::incident_mail(&config, &name, local_time, &incidents).await?;
+                Self::send_with_retries(&config, email, "incident report").await;
+            }
 
-            let mut body = format!(
-                "On {}, the Minecraft server \"{}\" encountered an incident.<br><br>&emsp;Error report:<br>{}<br><br>",
-                mail_requests[0].time,
-                name,
-                mail_requests[0].err_log.iter().fold(String::from("&emsp;"), |acc, x| (acc + "<br>&emsp;") + x),
-            );
+            for warning in warnings {
+                let mut values = template_values(&name, format_time(warning.time, local_time));
+                values.push(("{report}", escape_html(&warning.err_log.join("\n"))));
 
-            for x in mail_requests.iter().skip(1) {
-                body += &format!(
-                    "Additionally, on {}, another incident occured.<br><br>&emsp;Error report:<br>{}<br><br>",
-                    x.time,
-                    x.err_log
-                        .iter()
-                        .fold(String::from("&emsp;"), |acc, x| (acc + "<br>&emsp;") + x),
-                );
+                let email = Self::builder(&config)
+                    .header(ContentType::TEXT_HTML)
+                    .subject(fill_template(&config.templates.warning_subject, &values))
+                    .body(fill_template(&config.templates.warning_body, &values))?;
+                Self::send_with_retries(&config, email, "warning").await;
             }
 
             if is_final {
-                body += "<b>After this incident, the server manager stopped.</b><br>";
+                break;
             }
+        }
 
-            body += "End of report.";
+        Ok(())
+    }
 
-            let email = Message::builder()
-                .from(config.sender.clone())
-                .mailbox::<To>(config.contacts.clone().into())
-                .header(ContentType::TEXT_HTML)
-                .subject(subject)
-                .body(body)?;
+    async fn incident_mail(
+        config: &MailConfig,
+        name: &str,
+        local_time: bool,
+        mail_requests: &[MailRequest],
+    ) -> Result<Message> {
+        let is_final = mail_requests.iter().any(|x| x.final_incident);
 
-            let mut attempts = 0;
-            while let Err(err) = AsyncSmtpTransport::<AsyncStd1Executor>::relay("smtp.gmail.com")?
-                .credentials(config.credentials.clone())
-                .build()
-                .send(email.clone())
-                .await
-            {
-                attempts += 1;
-                if attempts > 5 {
-                    println!(
-                        "[ServerManager] [MAIL] Failed to send incident report:\n{}",
-                        err
-                    );
-                    break;
+        let mut values = template_values(name, format_time(mail_requests[0].time, local_time));
+
+        let subject = if is_final {
+            fill_template(&config.templates.final_incident_subject, &values)
+        } else {
+            fill_template(&config.templates.incident_subject, &values)
+        };
+
+        let mut truncated = false;
+
+        let mut report = format!(
+            "&emsp;Error report:<br>{}<br><br>",
+            inline_log(&mail_requests[0].err_log, &mut truncated),
+        );
+
+        for x in mail_requests.iter().skip(1) {
+            report += &format!(
+                    "Additionally, on {}, another incident occured.<br><br>&emsp;Error report:<br>{}<br><br>",
+                    format_time(x.time, local_time),
+                    inline_log(&x.err_log, &mut truncated),
+                );
+        }
+
+        if is_final {
+            report += "<b>After this incident, the server manager stopped.</b><br>";
+        }
+
+        report += "End of report.";
+
+        values.push(("{report}", report));
+        let body = fill_template(&config.templates.incident_body, &values);
+
+        let builder = Self::builder(config).subject(subject);
+
+        // Full reports are attached rather than inlined, as some providers
+        // reject messages with a very large body
+        let email = if truncated {
+            let full_log = mail_requests
+                .iter()
+                .map(|x| {
+                    format!(
+                        "On {}:\n{}\n",
+                        format_time(x.time, local_time),
+                        x.err_log.join("\n")
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+
+            let attachment = if full_log.len() > COMPRESS_ATTACHMENT_BYTES {
+                match Gzip::compress(full_log.as_bytes()).await {
+                    Ok(compressed) => Attachment::new(format!(
+                        "incident-{}.log.gz",
+                        mail_requests[0].time.format("%Y%m%d-%H%M%S")
+                    ))
+                    .body(compressed, ContentType::parse("application/gzip")?),
+                    Err(err) => {
+                        warn!(
+                            "[MAIL] Failed to compress incident report, attaching it as is:\n{}",
+                            err
+                        );
+                        Attachment::new("incident-report.txt".into())
+                            .body(full_log, ContentType::TEXT_PLAIN)
+                    }
                 }
-            }
+            } else {
+                Attachment::new("incident-report.txt".into())
+                    .body(full_log, ContentType::TEXT_PLAIN)
+            };
 
-            if is_final {
+            builder.multipart(
+                MultiPart::mixed()
+                    .singlepart(SinglePart::html(body))
+                    .singlepart(attachment),
+            )?
+        } else {
+            builder.header(ContentType::TEXT_HTML).body(body)?
+        };
+
+        Ok(email)
+    }
+
+    async fn send_with_retries(config: &MailConfig, email: Message, what: &str) {
+        let mut attempts = 0;
+        while let Err(err) = Self::send(config, email.clone()).await {
+            attempts += 1;
+            if attempts > 5 {
+                error!("[MAIL] Failed to send {}:\n{}", what, err);
                 break;
             }
+
+            async_std::task::sleep(Duration::from_secs(2u64.pow(attempts))).await;
         }
+    }
+}
 
-        Ok(())
+fn template_values(name: &str, time: String) -> Vec<(&'static str, String)> {
+    vec![
+        ("{name}", name.into()),
+        ("{time}", time),
+        ("{version}", env!("CARGO_PKG_VERSION").into()),
+    ]
+}
+
+// The configured time zone is applied to the whole process through `TZ`
+pub fn format_time(time: DateTime<Utc>, local: bool) -> String {
+    if local {
+        time.with_timezone(&Local).to_string()
+    } else {
+        time.to_string()
+    }
+}
+
+// Reports can contain server output, which must not be interpreted as markup
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn inline_log(err_log: &[String], truncated: &mut bool) -> String {
+    let omitted = err_log.len().saturating_sub(MAX_INLINE_LINES);
+    let head = if omitted > 0 {
+        MAX_INLINE_LINES / 2
+    } else {
+        err_log.len()
+    };
+
+    let mut res = String::from("&emsp;");
+    for (i, line) in err_log.iter().enumerate() {
+        if i == head && omitted > 0 {
+            res += &format!(
+                "<br>&emsp;<i>... {} lines omitted, see the attached report ...</i>",
+                omitted
+            );
+        }
+        if i >= head && i < head + omitted {
+            continue;
+        }
+
+        res += "<br>&emsp;";
+        if line.len() > MAX_INLINE_LINE_LEN {
+            let mut end = MAX_INLINE_LINE_LEN;
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            res += &escape_html(&line[..end]);
+            res += "...";
+            *truncated = true;
+        } else {
+            res += &escape_html(line);
+        }
+    }
+
+    if omitted > 0 {
+        *truncated = true;
     }
+    res
 }
@@ -1,48 +1,217 @@
-use std::path::PathBuf;
+use std::{io::Read, path::PathBuf};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 
 use crate::{
+    backup::BackupManager,
     config::{Config, ConfigSerialized},
-    server::ServerManager,
+    server::{ManagerExit, ServerManager},
 };
 
+#[macro_use]
+mod log;
+
 mod backup;
 mod cmd_utils;
 mod config;
+mod control;
+mod drain;
+mod idle;
 mod mail;
+mod memory;
+mod output;
+mod properties;
 mod rcon;
 mod server;
+mod slack;
+mod wake;
+mod webhook;
+
+#[cfg(test)]
+mod test_utils;
+
+fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>();
+    if let Some(pos) = args.iter().position(|arg| arg == "--quiet") {
+        args.remove(pos);
+        log::set_quiet();
+    }
 
-#[async_std::main]
-async fn main() -> Result<()> {
-    println!("[ServerManager] Fetching config...");
+    // Control commands are sent to the manager running in the working directory
+    let command = args.join(" ");
+    let is_command = matches!(
+        command.as_str(),
+        "status" | "stop" | "backup now" | "backup full" | "backup resume"
+    ) || command == "backup pause"
+        || command.starts_with("backup pause ");
 
-    let config_file = if let Some(config_path) = std::env::args().nth(1) {
-        PathBuf::from(config_path)
+    let config_arg = if is_command {
+        None
     } else {
-        PathBuf::from(".").join("server-manager.ron")
+        args.into_iter().next()
     };
-    let config = if config_file.exists() {
-        Config::try_from(config_file.as_ref()).await.map_err(|e| {
-            println!("[ServerManager] The provided file is not a valid configuration file.");
-            e
-        })?
+
+    // Read once, as the time zone is looked up before the config is fetched
+    let stdin_config = if config_arg.as_deref() == Some("-") {
+        let mut text = String::new();
+        std::io::stdin()
+            .read_to_string(&mut text)
+            .inspect_err(|_| {
+                error!("Failed to read the configuration from stdin.");
+            })?;
+        Some(text)
     } else {
-        if std::env::args().len() > 1 {
-            println!("[ServerManager] The provided file does not exist.");
+        None
+    };
+
+    set_timezone(config_arg.as_deref(), stdin_config.as_deref());
+
+    async_std::task::block_on(async {
+        if is_command {
+            run_command(&command).await
         } else {
-            ConfigSerialized::default().save(&config_file)?;
-            println!("[ServerManager] No manager configuration found.");
-            println!("[ServerManager] Generated a dummy configuration file.");
+            run(config_arg, stdin_config).await
         }
+    })
+}
 
-        return Ok(());
+// Makes local times use the configured time zone. Done before the runtime
+// starts, as the environment can only be changed safely while the process has
+// a single thread, and before chrono first reads the zone. An invalid config
+// is left to be reported when it is fetched.
+fn set_timezone(config_arg: Option<&str>, stdin_config: Option<&str>) {
+    let text = match (stdin_config, config_arg) {
+        (Some(text), _) => Some(text.to_string()),
+        (None, Some(path)) => std::fs::read_to_string(path).ok(),
+        (None, None) => std::env::var("SERVER_MANAGER_CONFIG")
+            .ok()
+            .or_else(|| std::fs::read_to_string("server-manager.ron").ok()),
     };
 
-    println!("[ServerManager] Starting server...");
+    let config = text.and_then(|text| ron::de::from_str::<ConfigSerialized>(&text).ok());
+    if let Some(timezone) = config.as_ref().and_then(|config| config.timezone()) {
+        std::env::set_var("TZ", timezone);
+    }
+}
 
-    ServerManager::start(config).await?;
+async fn run(config_arg: Option<String>, stdin_config: Option<String>) -> Result<()> {
+    let from_stdin = stdin_config.is_some();
+    let mut config = match fetch_config(config_arg, stdin_config, true).await? {
+        Some(config) => config,
+        None => return Ok(()),
+    };
+
+    // The new executable could not read the config again
+    if config.restart_on_update && from_stdin {
+        warn!("restart_on_update is not supported with a config read from stdin, ignoring it.");
+        config.restart_on_update = false;
+    }
+
+    // Resolved before the executable can be replaced
+    let executable = std::env::current_exe()?;
+
+    info!("Starting server...");
+
+    let failure_exit_code = config.failure_exit_code;
+    let exit = ServerManager::start(config).await?;
+
+    if server::update_requested() {
+        info!("Restarting the manager...");
+        let err = server::exec_manager(&executable);
+        error!("Failed to restart the manager.");
+        return Err(err.into());
+    }
+
+    if exit == ManagerExit::Failed {
+        std::process::exit(failure_exit_code.into());
+    }
 
     Ok(())
 }
+
+async fn run_command(command: &str) -> Result<()> {
+    if let Some(response) = control::request(command).await? {
+        println!("{}", response);
+        return Ok(());
+    }
+
+    // Without a running manager, commands are run directly when they can be
+    match command {
+        "status" => {
+            println!("{{\"ok\":true,\"running\":false}}");
+            Ok(())
+        }
+        "backup now" | "backup full" => {
+            let backups = fetch_config(None, None, false)
+                .await?
+                .and_then(|config| config.backups)
+                .ok_or_else(|| anyhow!("backups are not configured"))?;
+
+            cmd_utils::forward_signals_to_backups()?;
+            BackupManager::backup_once(&backups, command == "backup full")
+                .await
+                .map_err(|err| anyhow!(err))
+        }
+        _ => {
+            error!("No server manager is running in this folder.");
+            Err(anyhow!("no running server manager"))
+        }
+    }
+}
+
+// Returns None if there is no config to run with, in which case a dummy one
+// may have been generated. `stdin_config` is the config read from stdin, if
+// requested with `-`.
+async fn fetch_config(
+    config_arg: Option<String>,
+    stdin_config: Option<String>,
+    generate: bool,
+) -> Result<Option<Config>> {
+    info!("Fetching config...");
+
+    // The config can also be provided without a file, through stdin or the environment
+    let config_text = if stdin_config.is_some() {
+        stdin_config
+    } else if config_arg.is_none() {
+        std::env::var("SERVER_MANAGER_CONFIG").ok()
+    } else {
+        None
+    };
+
+    let config = if let Some(text) = config_text {
+        Config::try_from_str(&text).await.inspect_err(|_| {
+            error!("The provided configuration is not valid.");
+        })?
+    } else {
+        let config_file = if let Some(config_path) = &config_arg {
+            PathBuf::from(config_path)
+        } else {
+            PathBuf::from(".").join("server-manager.ron")
+        };
+
+        if config_file.exists() {
+            Config::try_from(config_file.as_ref())
+                .await
+                .inspect_err(|_| {
+                    error!("The provided file is not a valid configuration file.");
+                })?
+        } else {
+            if config_arg.is_some() {
+                error!("The provided file does not exist.");
+            } else if generate {
+                ConfigSerialized::default().save(&config_file)?;
+                info!("No manager configuration found.");
+                info!("Generated a dummy configuration file.");
+            } else {
+                error!("No manager configuration found.");
+            }
+
+            return Ok(None);
+        }
+    };
+
+    log::set_level(config.log_level);
+    log::set_name(&config.log_name);
+
+    Ok(Some(config))
+}
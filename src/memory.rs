@@ -0,0 +1,118 @@
+use std::time::{Duration, Instant};
+
+use async_std::{
+    channel::{self, Sender},
+    future::pending,
+    prelude::FutureExt as AsyncStdFutureExt,
+};
+
+use crate::{
+    config::{Config, ShutdownMethod},
+    drain::drain,
+    rcon::MinecraftCommand,
+};
+
+const GRACE_PERIOD: Duration = Duration::from_secs(5 * 60);
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+// Short spikes, such as while generating chunks, do not cause a restart
+const SUSTAINED_FOR: Duration = Duration::from_secs(5 * 60);
+const RESTART_WARNING: Duration = Duration::from_secs(60);
+
+pub struct MemoryWatcher;
+
+impl MemoryWatcher {
+    // Resolves once the server used more than the threshold of memory for
+    // long enough, after warning or draining players and asking it to stop
+    // through RCON unless it is stopped with a signal.
+    pub async fn start(config: Config, pid: u32, cmd_chan: Sender<MinecraftCommand>) {
+        let threshold_mb = match config.restart_memory_threshold_mb {
+            Some(threshold) => threshold,
+            None => pending().await,
+        };
+
+        // The JVM claims most of its heap while the server starts
+        async_std::task::sleep(GRACE_PERIOD).await;
+
+        let mut above_since = None;
+        loop {
+            async_std::task::sleep(POLL_INTERVAL).await;
+
+            let rss_mb = match resident_memory_mb(pid) {
+                Ok(rss_mb) => rss_mb,
+                Err(err) => {
+                    warn!(
+                        "[MEMORY] Failed to read server memory usage, no longer watching it:\n{}",
+                        err
+                    );
+                    pending().await
+                }
+            };
+            debug!("[MEMORY] The server uses {} MB of memory.", rss_mb);
+
+            if rss_mb <= threshold_mb {
+                above_since = None;
+                continue;
+            }
+
+            let since = *above_since.get_or_insert_with(Instant::now);
+            if since.elapsed() >= SUSTAINED_FOR {
+                info!(
+                    "[MEMORY] The server used more than {} MB of memory for too long, restarting it.",
+                    threshold_mb
+                );
+                break;
+            }
+        }
+
+        if let Some(drain_config) = &config.drain {
+            drain(
+                drain_config,
+                &config.server_folder,
+                &config.command_profile,
+                &cmd_chan,
+            )
+            .await;
+        } else {
+            let msg = format!(
+                "Server restarting in {} seconds.",
+                RESTART_WARNING.as_secs()
+            );
+            cmd_chan
+                .send(MinecraftCommand::Broadcast(msg))
+                .timeout(Duration::from_secs(10))
+                .await
+                .ok();
+            async_std::task::sleep(RESTART_WARNING).await;
+        }
+
+        if config.shutdown_method == ShutdownMethod::Signal {
+            return;
+        }
+
+        // Wait for the server to acknowledge so the command is not lost
+        let (resp_send, resp_rec) = channel::bounded(1);
+        if cmd_chan
+            .send(MinecraftCommand::Raw(
+                config.command_profile.stop.clone(),
+                resp_send,
+            ))
+            .timeout(Duration::from_secs(10))
+            .await
+            .is_ok()
+        {
+            resp_rec.recv().timeout(Duration::from_secs(30)).await.ok();
+        }
+    }
+}
+
+fn resident_memory_mb(pid: u32) -> std::io::Result<u64> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid))?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))
+        .and_then(|value| value.trim().strip_suffix("kB"))
+        .and_then(|kb| kb.trim().parse::<u64>().ok())
+        .map(|kb| kb / 1024)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "no VmRSS entry"))
+}
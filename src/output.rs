@@ -0,0 +1,156 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_std::{
+    channel::Sender,
+    io::{prelude::BufReadExt, BufReader, Read, Write, WriteExt},
+};
+
+const MAX_BYTES: usize = 64 * 1024;
+const MAX_LINE_BYTES: usize = 4 * 1024;
+
+// Fatal signatures printed later, such as by a player in chat, are ignored
+const STARTUP_WINDOW: Duration = Duration::from_secs(60);
+
+// Output of startup errors that restarting the server cannot fix
+const FATAL_SIGNATURES: [(&str, &str); 6] = [
+    (
+        "UnsupportedClassVersionError",
+        "The server jar requires a newer version of Java.",
+    ),
+    (
+        "Unsupported class file major version",
+        "The server jar requires a newer version of Java.",
+    ),
+    (
+        "Unable to access jarfile",
+        "Java cannot access the server jar.",
+    ),
+    ("Invalid or corrupt jarfile", "The server jar is corrupted."),
+    (
+        "Could not create the Java Virtual Machine",
+        "Java failed to start, check java_args.",
+    ),
+    (
+        "You need to agree to the EULA",
+        "The EULA was not accepted, set eula=true in eula.txt.",
+    ),
+];
+
+// Keeps the last lines printed by the server, bounded both in line count
+// and in total size so that a spamming server cannot use unbounded memory.
+pub struct OutputBuffer {
+    lines: VecDeque<String>,
+    bytes: usize,
+    max_lines: usize,
+    fatal_error: Option<&'static str>,
+    // Fatal signatures are only looked for until then, or until the server is ready
+    startup_until: Option<Instant>,
+    // Notified once when a line matches the pattern
+    ready: Option<(String, Sender<()>)>,
+}
+
+impl OutputBuffer {
+    pub fn new(max_lines: usize, ready: Option<(String, Sender<()>)>) -> Self {
+        Self {
+            lines: VecDeque::with_capacity(max_lines),
+            bytes: 0,
+            max_lines,
+            fatal_error: None,
+            startup_until: Some(Instant::now() + STARTUP_WINDOW),
+            ready,
+        }
+    }
+
+    pub fn push(&mut self, mut line: String) {
+        // Checked on every line as the buffer may not keep them all
+        if let Some(until) = self.startup_until {
+            if Instant::now() >= until {
+                self.startup_until = None;
+            } else if let Some((_, reason)) = FATAL_SIGNATURES
+                .iter()
+                .find(|(signature, _)| line.contains(signature))
+            {
+                self.fatal_error = Some(reason);
+                self.startup_until = None;
+            }
+        }
+
+        if let Some((pattern, ready)) = &self.ready {
+            if matches_pattern(pattern, &line) {
+                ready.try_send(()).ok();
+                self.ready = None;
+                self.startup_until = None;
+            }
+        }
+
+        if line.len() > MAX_LINE_BYTES {
+            let mut end = MAX_LINE_BYTES;
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            line.truncate(end);
+            line.push_str("...");
+        }
+
+        self.bytes += line.len();
+        self.lines.push_back(line);
+
+        while self.lines.len() > self.max_lines || self.bytes > MAX_BYTES {
+            if let Some(old) = self.lines.pop_front() {
+                self.bytes -= old.len();
+            }
+        }
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        self.lines.iter().cloned().collect()
+    }
+
+    pub fn fatal_error(&self) -> Option<&'static str> {
+        self.fatal_error
+    }
+}
+
+// Matches `pattern` anywhere in `line`, with `*` matching any text. This is a
+// glob, other characters such as `.` or `(` only match themselves.
+fn matches_pattern(pattern: &str, line: &str) -> bool {
+    let mut rest = line;
+    for part in pattern.split('*') {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+// Copies the output of the server to `to` while recording it in `buffer`.
+// Output is forwarded as raw bytes so that invalid UTF-8 never stops the copy,
+// which would eventually block the server on a full pipe.
+pub async fn capture(
+    from: impl Read + Unpin,
+    mut to: impl Write + Unpin,
+    buffer: Arc<Mutex<OutputBuffer>>,
+) {
+    let mut from = BufReader::new(from);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match from.read_until(b'\n', &mut line).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => (),
+        }
+
+        to.write_all(&line).await.ok();
+        to.flush().await.ok();
+
+        let text = String::from_utf8_lossy(&line);
+        if let Ok(mut buffer) = buffer.lock() {
+            buffer.push(text.trim_end_matches(['\n', '\r']).to_string());
+        }
+    }
+}
@@ -0,0 +1,372 @@
+use std::{
+    os::unix::fs::{chown, MetadataExt},
+    path::Path,
+};
+
+use anyhow::{anyhow, Result};
+
+pub struct Properties {
+    lines: Vec<Line>,
+}
+
+struct Line {
+    raw: String,
+    entry: Option<(String, String)>,
+}
+
+impl Properties {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("failed to read properties file at {:?}:\n{}", path, e))?;
+        Ok(Self::parse(&content))
+    }
+
+    pub fn parse(content: &str) -> Self {
+        let mut parsed = Vec::new();
+
+        let mut lines = content.lines();
+        while let Some(line) = lines.next() {
+            let mut raw = String::from(line);
+            let mut logical = String::from(trim_start(line));
+            if logical.is_empty() || logical.starts_with('#') || logical.starts_with('!') {
+                parsed.push(Line { raw, entry: None });
+                continue;
+            }
+
+            // A line ending with an odd number of backslashes continues on the next line.
+            while ends_with_continuation(&logical) {
+                logical.pop();
+                match lines.next() {
+                    Some(next) => {
+                        raw.push('\n');
+                        raw.push_str(next);
+                        logical.push_str(trim_start(next));
+                    }
+                    None => break,
+                }
+            }
+
+            parsed.push(Line {
+                raw,
+                entry: Some(split_entry(&logical)),
+            });
+        }
+
+        Self { lines: parsed }
+    }
+
+    // Like Java, the last occurrence of a key wins.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.lines
+            .iter()
+            .rev()
+            .filter_map(|l| l.entry.as_ref())
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    // Returns whether the file content changed.
+    pub fn set(&mut self, key: &str, value: &str) -> bool {
+        if self.get(key) == Some(value) {
+            return false;
+        }
+
+        let raw = format!("{}={}", escape(key, true), escape(value, false));
+        let mut found = false;
+        for line in &mut self.lines {
+            if matches!(&line.entry, Some((k, _)) if k == key) {
+                line.raw = raw.clone();
+                line.entry = Some((key.into(), value.into()));
+                found = true;
+            }
+        }
+
+        if !found {
+            self.lines.push(Line {
+                raw,
+                entry: Some((key.into(), value.into())),
+            });
+        }
+
+        true
+    }
+
+    // Written next to the file then renamed, so that an interrupted write does
+    // not truncate it. The new file keeps the permissions and owner of the old
+    // one, as the server may run as another user.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut content = String::new();
+        for line in &self.lines {
+            content += &line.raw;
+            content.push('\n');
+        }
+
+        let tmp = path.with_extension("properties.tmp");
+        let write = || -> std::io::Result<()> {
+            std::fs::write(&tmp, &content)?;
+            if let Ok(metadata) = std::fs::metadata(path) {
+                std::fs::set_permissions(&tmp, metadata.permissions())?;
+                chown(&tmp, Some(metadata.uid()), Some(metadata.gid())).ok();
+            }
+            std::fs::rename(&tmp, path)
+        };
+
+        write().map_err(|e| {
+            std::fs::remove_file(&tmp).ok();
+            anyhow!("failed to write properties file at {:?}:\n{}", path, e)
+        })
+    }
+
+    // Applies the given values to the properties file at `path`, creating it if
+    // needed. The first time an existing file gets modified, a copy of it is kept
+    // next to it with a `.bak` extension.
+    pub fn update(path: &Path, values: &[(&str, &str)]) -> Result<()> {
+        let exists = path.exists();
+        let mut properties = if exists {
+            Self::load(path)?
+        } else {
+            Self::parse("")
+        };
+
+        let mut changed = false;
+        for (key, value) in values {
+            changed |= properties.set(key, value);
+        }
+
+        if !changed {
+            return Ok(());
+        }
+
+        if exists {
+            let backup = path.with_extension("properties.bak");
+            if !backup.exists() {
+                std::fs::copy(path, &backup).map_err(|e| {
+                    anyhow!("failed to back up properties file to {:?}:\n{}", backup, e)
+                })?;
+            }
+        }
+
+        properties.save(path)
+    }
+}
+
+fn trim_start(line: &str) -> &str {
+    line.trim_start_matches([' ', '\t', '\x0c'])
+}
+
+fn ends_with_continuation(line: &str) -> bool {
+    line.chars().rev().take_while(|c| *c == '\\').count() % 2 == 1
+}
+
+fn split_entry(line: &str) -> (String, String) {
+    let mut key_end = line.len();
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if matches!(c, '=' | ':' | ' ' | '\t' | '\x0c') {
+            key_end = i;
+            break;
+        }
+    }
+
+    let (key, rest) = line.split_at(key_end);
+    let rest = trim_start(rest);
+    let rest = rest
+        .strip_prefix('=')
+        .or_else(|| rest.strip_prefix(':'))
+        .map(trim_start)
+        .unwrap_or(rest);
+
+    (unescape(key), unescape(rest))
+}
+
+fn unescape(raw: &str) -> String {
+    let mut res = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            res.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('t') => res.push('\t'),
+            Some('n') => res.push('\n'),
+            Some('r') => res.push('\r'),
+            Some('f') => res.push('\x0c'),
+            Some('u') => {
+                let code: String = chars.clone().take(4).collect();
+                match u32::from_str_radix(&code, 16).ok().and_then(char::from_u32) {
+                    Some(decoded) if code.len() == 4 => {
+                        res.push(decoded);
+                        chars.nth(3);
+                    }
+                    _ => res.push('u'),
+                }
+            }
+            Some(other) => res.push(other),
+            None => (),
+        }
+    }
+    res
+}
+
+fn escape(text: &str, is_key: bool) -> String {
+    let mut res = String::with_capacity(text.len());
+    for (i, c) in text.chars().enumerate() {
+        match c {
+            '\\' => res.push_str("\\\\"),
+            '\t' => res.push_str("\\t"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\x0c' => res.push_str("\\f"),
+            '=' | ':' | '#' | '!' => {
+                res.push('\\');
+                res.push(c);
+            }
+            ' ' if is_key || i == 0 => res.push_str("\\ "),
+            _ => res.push(c),
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    #[test]
+    fn separators() {
+        let properties = Properties::parse("a=1\nb:2\nc 3\nd = 4\ne\t:\t5\nf\ng==6\n");
+        assert_eq!(properties.get("a"), Some("1"));
+        assert_eq!(properties.get("b"), Some("2"));
+        assert_eq!(properties.get("c"), Some("3"));
+        assert_eq!(properties.get("d"), Some("4"));
+        assert_eq!(properties.get("e"), Some("5"));
+        assert_eq!(properties.get("f"), Some(""));
+        // Only the first separator splits the entry
+        assert_eq!(properties.get("g"), Some("=6"));
+    }
+
+    #[test]
+    fn comments_and_blank_lines() {
+        let properties = Properties::parse("# a=1\n  ! b=2\n\n   \nc=3 # not a comment\n");
+        assert_eq!(properties.get("a"), None);
+        assert_eq!(properties.get("b"), None);
+        assert_eq!(properties.get("# a"), None);
+        assert_eq!(properties.get("c"), Some("3 # not a comment"));
+    }
+
+    #[test]
+    fn continuation_lines() {
+        let properties = Properties::parse("motd=first \\\n    second\\\n\tthird\nnext=1\n");
+        assert_eq!(properties.get("motd"), Some("first secondthird"));
+        assert_eq!(properties.get("next"), Some("1"));
+
+        // An even number of backslashes is an escaped backslash instead
+        let properties = Properties::parse("path=C:\\\\\nnext=1\n");
+        assert_eq!(properties.get("path"), Some("C:\\"));
+        assert_eq!(properties.get("next"), Some("1"));
+
+        // A continuation on the last line is ignored
+        let properties = Properties::parse("last=1\\");
+        assert_eq!(properties.get("last"), Some("1"));
+    }
+
+    #[test]
+    fn escapes() {
+        let properties = Properties::parse(
+            "motd=\\u00a7aHello\\tWorld\\n\nkey\\ with\\:seps=\\=x\nbad=\\u00zz\nshort=\\u12\n",
+        );
+        assert_eq!(properties.get("motd"), Some("\u{a7}aHello\tWorld\n"));
+        assert_eq!(properties.get("key with:seps"), Some("=x"));
+        assert_eq!(properties.get("bad"), Some("u00zz"));
+        assert_eq!(properties.get("short"), Some("u12"));
+    }
+
+    #[test]
+    fn last_occurrence_wins() {
+        let properties = Properties::parse("a=1\na=2\n");
+        assert_eq!(properties.get("a"), Some("2"));
+    }
+
+    #[test]
+    fn set_escapes_and_round_trips() {
+        let mut properties = Properties::parse("");
+        assert!(properties.set("key with:seps", " value=\\\t"));
+        assert!(!properties.set("key with:seps", " value=\\\t"));
+
+        let mut content = String::new();
+        for line in &properties.lines {
+            content += &line.raw;
+            content.push('\n');
+        }
+        assert_eq!(
+            Properties::parse(&content).get("key with:seps"),
+            Some(" value=\\\t")
+        );
+    }
+
+    #[test]
+    fn update_keeps_comments_and_order() {
+        let dir = TempDir::new();
+        let path = dir.write(
+            "server.properties",
+            "#Minecraft server properties\n\
+             enable-rcon=false\n\
+             # kept\n\
+             motd=A \\\n  server\n\
+             rcon.port=25575\n",
+        );
+
+        Properties::update(&path, &[("enable-rcon", "true"), ("rcon.password", "pw")]).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "#Minecraft server properties\n\
+             enable-rcon=true\n\
+             # kept\n\
+             motd=A \\\n  server\n\
+             rcon.port=25575\n\
+             rcon.password=pw\n"
+        );
+    }
+
+    #[test]
+    fn update_backs_up_the_original_once() {
+        let dir = TempDir::new();
+        let path = dir.write("server.properties", "a=1\n");
+        let backup = dir.path().join("server.properties.bak");
+
+        Properties::update(&path, &[("a", "1")]).unwrap();
+        assert!(!backup.exists());
+
+        Properties::update(&path, &[("a", "2")]).unwrap();
+        Properties::update(&path, &[("a", "3")]).unwrap();
+        assert_eq!(std::fs::read_to_string(&backup).unwrap(), "a=1\n");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "a=3\n");
+    }
+
+    #[test]
+    fn update_replaces_the_file() {
+        let dir = TempDir::new();
+        let path = dir.write("server.properties", "a=1\n");
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+
+        Properties::update(&path, &[("a", "2")]).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(!dir.path().join("server.properties.tmp").exists());
+
+        // Missing files are created
+        let path = dir.path().join("new.properties");
+        Properties::update(&path, &[("a", "1")]).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "a=1\n");
+        assert!(!dir.path().join("new.properties.bak").exists());
+    }
+}
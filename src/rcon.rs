@@ -1,61 +1,165 @@
-use std::time::{Duration, Instant};
+use std::{
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
 
 use anyhow::Result;
-use async_std::channel::{Receiver, Sender};
+use async_std::{
+    channel::{Receiver, Sender},
+    prelude::FutureExt as AsyncStdFutureExt,
+};
 use rcon::Connection;
 
-use crate::config::Config;
+use crate::{
+    config::{BroadcastStyle, Config, HealthCheck, TextComponent},
+    server::{Decision, IncidentTracker},
+};
+
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Failures less than this apart are counted as recent
+const INCIDENT_WINDOW: Duration = Duration::from_secs(10 * 60);
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
 
 pub enum MinecraftCommand {
     SaveOn,
     SaveAll(bool),
-    SaveOff,
+    // The response is sent back if requested
+    SaveOff(Option<Sender<String>>),
     Broadcast(String),
     Await(Sender<()>),
+    Raw(String, Sender<String>),
+}
+
+pub struct RconFailure {
+    pub err_log: Vec<String>,
+    // Reconnecting or restarting the server cannot fix the failure
+    pub fatal: bool,
 }
 
 pub struct RconError {
     error: anyhow::Error,
     cmd: Option<MinecraftCommand>,
+    fatal: bool,
 }
 
-impl<T> From<T> for RconError where T: std::error::Error + Send + Sync + 'static {
+impl<T> From<T> for RconError
+where
+    T: std::error::Error + Send + Sync + 'static,
+{
     fn from(err: T) -> Self {
         Self {
             error: err.into(),
             cmd: None,
+            fatal: false,
         }
     }
 }
 
+// Commands are sent through this trait rather than to an rcon connection
+// directly, so that the connection logic can run against any implementation,
+// such as a fake server.
+trait RconConnection {
+    async fn cmd(&mut self, cmd: &str) -> Result<String, rcon::Error>;
+}
+
+impl RconConnection for Connection {
+    async fn cmd(&mut self, cmd: &str) -> Result<String, rcon::Error> {
+        Connection::cmd(self, cmd).await
+    }
+}
+
+// Kept across reconnections to the same server
+#[derive(Default)]
+struct Session {
+    ran_on_connect: bool,
+    health_failures: u32,
+}
+
 pub struct RconManager;
 
 impl RconManager {
-    pub async fn start(config: Config, chan: Receiver<MinecraftCommand>) -> Vec<String> {
-        let mut last_incident = Instant::now();
-        let mut recent_incidents = 0;
+    pub async fn start(
+        config: Config,
+        chan: Receiver<MinecraftCommand>,
+        contact: Sender<()>,
+    ) -> RconFailure {
+        let address = Self::address(&config);
+        let connect = || {
+            rcon::Connection::builder()
+                .enable_minecraft_quirks(config.rcon_minecraft_quirks)
+                .connect(address.as_str(), &config.rcon_password)
+        };
+
+        let incidents = IncidentTracker::new(Instant::now(), INCIDENT_WINDOW, RECONNECT_DELAY);
+        Self::run(&config, connect, &chan, &contact, incidents).await
+    }
+
+    // Opens connections with `connect`, reconnecting after failures until
+    // `incidents` tells they happen too often.
+    async fn run<C, F>(
+        config: &Config,
+        connect: impl Fn() -> F,
+        chan: &Receiver<MinecraftCommand>,
+        contact: &Sender<()>,
+        mut incidents: IncidentTracker,
+    ) -> RconFailure
+    where
+        C: RconConnection,
+        F: Future<Output = Result<C, rcon::Error>>,
+    {
         let mut first_attempt = true;
 
         let mut first_attempt_attempts = 0;
 
         let mut pending_msg = None;
+        let mut session = Session::default();
 
         let err_log = loop {
-            if let Err(err) = Self::inner(&config, &chan, first_attempt, pending_msg).await {
-                println!("[ServerManager] [RCON] Unexpected failure.\n{}", err.error);
+            if let Err(err) = Self::inner(
+                &connect,
+                config,
+                chan,
+                contact,
+                first_attempt,
+                pending_msg,
+                &mut session,
+            )
+            .await
+            {
+                if err.fatal {
+                    return RconFailure {
+                        err_log: vec![format!("[RCON] {}", err.error)],
+                        fatal: true,
+                    };
+                }
 
-                first_attempt = false;
+                error!("[RCON] Unexpected failure.\n{}", err.error);
 
-                if (Instant::now() - last_incident) > Duration::from_secs(10 * 60) {
-                    recent_incidents = 0;
+                // A hung server may still accept connections, so failed
+                // checks are counted across reconnections
+                if let Some(check) = &config.health_check {
+                    if session.health_failures >= check.fail_threshold {
+                        break vec![format!(
+                            "[RCON] Server did not answer {} health checks in a row, it is likely hung.",
+                            session.health_failures
+                        )];
+                    }
                 }
 
-                if recent_incidents > 5 {
-                    break vec!["[RCON] Too many RCON incidents in a short period of time.".into()];
-                } else {
-                    last_incident = Instant::now();
-                    println!("[ServerManager] [RCON] Reconnecting...");
-                    async_std::task::sleep(Duration::from_secs(1)).await;
+                first_attempt = false;
+
+                match incidents.record_incident(Instant::now(), None) {
+                    Decision::Bailout => {
+                        break vec![
+                            "[RCON] Too many RCON incidents in a short period of time.".into()
+                        ];
+                    }
+                    Decision::Restart(delay) => {
+                        warn!("[RCON] Reconnecting...");
+                        async_std::task::sleep(delay).await;
+                    }
                 }
 
                 pending_msg = err.cmd;
@@ -72,78 +176,539 @@ impl RconManager {
             }
         };
 
-        err_log
+        RconFailure {
+            err_log,
+            fatal: false,
+        }
+    }
+
+    // Asks the server to stop over a short-lived connection, if RCON is reachable.
+    pub async fn stop(config: &Config) -> Result<()> {
+        let mut conn = Self::announce_shutdown(config).await?;
+
+        // The server may close the connection before answering while it stops
+        conn.cmd(&config.command_profile.stop)
+            .timeout(Duration::from_secs(5))
+            .await
+            .ok();
+        Ok(())
+    }
+
+    // Broadcasts the shutdown announcement over a short-lived connection, which
+    // is returned, if RCON is reachable. Only announced when RCON works, as it
+    // is often the thing that failed.
+    pub async fn announce_shutdown(config: &Config) -> Result<Connection> {
+        let mut conn = rcon::Connection::builder()
+            .enable_minecraft_quirks(config.rcon_minecraft_quirks)
+            .connect(Self::address(config), &config.rcon_password)
+            .timeout(Duration::from_secs(5))
+            .await??;
+
+        if let Some(announcement) = &config.shutdown_announcement {
+            conn.cmd(&Self::broadcast_command(config, announcement))
+                .timeout(Duration::from_secs(5))
+                .await
+                .ok();
+        }
+        Ok(conn)
     }
 
-    async fn inner(
+    fn address(config: &Config) -> String {
+        // IPv6 literals must be bracketed to be followed by a port
+        match config.rcon_host.parse::<IpAddr>() {
+            Ok(ip) => SocketAddr::new(ip, config.rcon_port).to_string(),
+            Err(_) => format!("{}:{}", config.rcon_host, config.rcon_port),
+        }
+    }
+
+    async fn inner<C, F>(
+        connect: &impl Fn() -> F,
         config: &Config,
         chan: &Receiver<MinecraftCommand>,
+        contact: &Sender<()>,
         first_attempt: bool,
         pending_message: Option<MinecraftCommand>,
-    ) -> Result<(), RconError> {
-        let address = String::from("localhost:") + &config.rcon_port.to_string();
+        session: &mut Session,
+    ) -> Result<(), RconError>
+    where
+        C: RconConnection,
+        F: Future<Output = Result<C, rcon::Error>>,
+    {
+        // The pending message is handed back on failure, so that it survives
+        // several reconnection attempts. Backups wait on it when it is an
+        // `Await` barrier.
+        let connect = connect();
 
-        let mut conn = match rcon::Connection::builder()
-            .enable_minecraft_quirks(true)
-            .connect(address, &config.rcon_password)
-            .await
-        {
-            Ok(conn) => conn,
-            Err(err) => match err {
-                rcon::Error::Io(err) => {
-                    return if first_attempt {
-                        Ok(())
-                    } else {
-                        Err(err.into())
-                    }
+        // A hung server can also accept connections without ever
+        // authenticating them
+        let connected = match &config.health_check {
+            Some(check) => match connect.timeout(HEALTH_CHECK_TIMEOUT).await {
+                Ok(connected) => connected,
+                Err(_) => {
+                    session.health_failures += 1;
+                    return Err(RconError {
+                        error: anyhow::anyhow!(
+                            "Connection timed out ({}/{}).",
+                            session.health_failures,
+                            check.fail_threshold
+                        ),
+                        cmd: pending_message,
+                        fatal: false,
+                    });
                 }
-                x => return Err(x.into()),
             },
+            None => connect.await,
+        };
+
+        let mut conn = match connected {
+            Ok(conn) => conn,
+            Err(rcon::Error::Io(_)) if first_attempt => return Ok(()),
+            // The password will not become right by reconnecting
+            Err(rcon::Error::Auth) => {
+                return Err(RconError {
+                    error: anyhow::anyhow!(
+                        "RCON password mismatch, check rcon_password in the config against rcon.password in server.properties."
+                    ),
+                    cmd: pending_message,
+                    fatal: true,
+                })
+            }
+            Err(err) => {
+                return Err(RconError {
+                    error: err.into(),
+                    cmd: pending_message,
+                    fatal: false,
+                })
+            }
         };
 
-        println!("[ServerManager] [RCON] Acquired connection to server.");
+        info!("[RCON] Acquired connection to server.");
+        contact.try_send(()).ok();
+
+        // Only run once per server start, not again after reconnecting. With a
+        // ready pattern, they are run once the server is ready instead.
+        if !session.ran_on_connect && config.ready_pattern.is_none() {
+            session.ran_on_connect = true;
+            for command in &config.on_connect_commands {
+                match conn.cmd(command).await {
+                    Ok(response) => log_command_response(command, &response),
+                    Err(rcon::Error::Io(err)) => {
+                        return Err(RconError {
+                            error: err.into(),
+                            cmd: pending_message,
+                            fatal: false,
+                        })
+                    }
+                    Err(err) => warn!("[RCON] Failed to run `{}`:\n{}", command, err),
+                }
+            }
+        }
 
         if let Some(pending) = pending_message {
-            if let Err(error) = Self::send_message(&mut conn, &pending).await {
+            if let Err(error) = Self::send_message(&mut conn, config, &pending).await {
                 return Err(RconError {
                     error: error.into(),
                     cmd: Some(pending),
+                    fatal: false,
                 });
             }
 
-            println!("[ServerManager] [RCON] Pending message processed.");
+            info!("[RCON] Pending message processed.");
         }
 
+        let mut next_health_check = Instant::now()
+            + config
+                .health_check
+                .as_ref()
+                .map_or(Duration::ZERO, |check| check.interval);
+
         loop {
-            let cmd = chan.recv().await?;
-            if let Err(error) = Self::send_message(&mut conn, &cmd).await {
+            let cmd = match &config.health_check {
+                Some(check) => {
+                    let remaining = next_health_check.saturating_duration_since(Instant::now());
+                    match chan.recv().timeout(remaining).await {
+                        Ok(cmd) => cmd?,
+                        Err(_) => {
+                            Self::health_check(&mut conn, check, session).await?;
+                            next_health_check = Instant::now() + check.interval;
+                            continue;
+                        }
+                    }
+                }
+                None => chan.recv().await?,
+            };
+
+            if let Err(error) = Self::send_message(&mut conn, config, &cmd).await {
                 return Err(RconError {
                     error: error.into(),
                     cmd: Some(cmd),
+                    fatal: false,
                 });
             }
         }
     }
 
-    async fn send_message(conn: &mut Connection, cmd: &MinecraftCommand) -> Result<(), rcon::Error> {
+    // A check that timed out leaves its response pending on the connection,
+    // so the connection is reset after it.
+    async fn health_check(
+        conn: &mut impl RconConnection,
+        check: &HealthCheck,
+        session: &mut Session,
+    ) -> Result<(), RconError> {
+        debug!("[RCON] Sending health check `{}`.", check.command);
+        match conn.cmd(&check.command).timeout(HEALTH_CHECK_TIMEOUT).await {
+            Ok(Ok(_)) => {
+                session.health_failures = 0;
+                Ok(())
+            }
+            Ok(Err(err)) => Err(err.into()),
+            Err(_) => {
+                session.health_failures += 1;
+                Err(RconError {
+                    error: anyhow::anyhow!(
+                        "Health check `{}` timed out ({}/{}).",
+                        check.command,
+                        session.health_failures,
+                        check.fail_threshold
+                    ),
+                    cmd: None,
+                    fatal: false,
+                })
+            }
+        }
+    }
+
+    async fn send_message(
+        conn: &mut impl RconConnection,
+        config: &Config,
+        cmd: &MinecraftCommand,
+    ) -> Result<(), rcon::Error> {
+        let profile = &config.command_profile;
         match &cmd {
-            MinecraftCommand::SaveOn => conn.cmd("save-on").await.map(drop),
+            MinecraftCommand::SaveOn => conn.cmd(&profile.save_on).await.map(drop),
             MinecraftCommand::SaveAll(flush) => conn
-                .cmd(if *flush { "save-all flush" } else { "save-all" })
+                .cmd(if *flush {
+                    &profile.save_all_flush
+                } else {
+                    &profile.save_all
+                })
                 .await
                 .map(drop),
-            MinecraftCommand::SaveOff => conn.cmd("save-off").await.map(drop),
+            MinecraftCommand::SaveOff(back) => {
+                let response = conn.cmd(&profile.save_off).await?;
+                if let Some(back) = back {
+                    back.try_send(response).ok();
+                }
+                Ok(())
+            }
             MinecraftCommand::Broadcast(msg) => conn
-                .cmd(&format!(
-                    "tellraw @a {{\"text\":\"{}\",\"color\":\"light_purple\"}}",
-                    msg
-                ))
+                .cmd(&Self::broadcast_command(config, msg))
                 .await
                 .map(drop),
             MinecraftCommand::Await(back) => {
                 back.send(()).await.ok();
                 Ok(())
             }
+            MinecraftCommand::Raw(raw, back) => {
+                debug!("[RCON] Sending command `{}`.", raw);
+                let response = conn.cmd(raw).await?;
+                // Never block the connection on a requester that stopped waiting
+                back.try_send(response).ok();
+                Ok(())
+            }
+        }
+    }
+
+    fn broadcast_command(config: &Config, msg: &str) -> String {
+        if let Some(broadcast) = &config.command_profile.broadcast {
+            return broadcast.replace("{message}", &msg.replace(['\n', '\r'], " "));
+        }
+
+        match config.broadcast_style {
+            BroadcastStyle::Tellraw => format!(
+                "tellraw @a {}",
+                Self::tellraw_json(&config.broadcast_format, msg)
+            ),
+            BroadcastStyle::Say => format!("say {}", Self::say_text(&config.broadcast_format, msg)),
+        }
+    }
+
+    fn say_text(format: &[TextComponent], msg: &str) -> String {
+        format
+            .iter()
+            .map(|c| c.text.replace("{message}", msg))
+            .collect::<String>()
+            .replace(['\n', '\r'], " ")
+    }
+
+    fn tellraw_json(format: &[TextComponent], msg: &str) -> String {
+        // The leading empty component keeps styles from leaking between components.
+        let mut json = String::from("[\"\"");
+        for component in format {
+            json += &format!(
+                ",{{\"text\":{}",
+                json_string(&component.text.replace("{message}", msg))
+            );
+            if let Some(color) = &component.color {
+                json += &format!(",\"color\":{}", json_string(color));
+            }
+            if component.bold {
+                json += ",\"bold\":true";
+            }
+            if component.italic {
+                json += ",\"italic\":true";
+            }
+            json.push('}');
+        }
+        json.push(']');
+        json
+    }
+}
+
+pub fn log_command_response(command: &str, response: &str) {
+    if response.trim().is_empty() {
+        info!("[RCON] Ran `{}`.", command);
+    } else if is_command_error(response) {
+        warn!("[RCON] Failed to run `{}`: {}", command, response.trim());
+    } else {
+        info!("[RCON] Ran `{}`: {}", command, response.trim());
+    }
+}
+
+// Minecraft reports invalid commands in the response rather than as an error
+fn is_command_error(response: &str) -> bool {
+    response.starts_with("Unknown or incomplete command")
+        || response.starts_with("Unknown command")
+        || response.starts_with("Incorrect argument for command")
+}
+
+pub fn json_string(text: &str) -> String {
+    let mut res = String::with_capacity(text.len() + 2);
+    res.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if (c as u32) < 0x20 => res += &format!("\\u{:04x}", c as u32),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use async_std::channel;
+
+    use super::*;
+    use crate::{config::tests::test_config, server::MAX_RECENT_INCIDENTS, test_utils::TempDir};
+
+    // Stands in for a server, recording the commands it ran or failing them
+    // as if the connection broke
+    struct FakeConnection {
+        ran: Arc<Mutex<Vec<String>>>,
+        broken: bool,
+    }
+
+    impl RconConnection for FakeConnection {
+        async fn cmd(&mut self, cmd: &str) -> Result<String, rcon::Error> {
+            if self.broken {
+                return Err(rcon::Error::Io(io::ErrorKind::BrokenPipe.into()));
+            }
+
+            self.ran.lock().unwrap().push(cmd.into());
+            Ok(format!("ran {}", cmd))
+        }
+    }
+
+    // Opens connections as decided by `open` from the number of previous attempts
+    struct FakeServer {
+        ran: Arc<Mutex<Vec<String>>>,
+        attempts: AtomicUsize,
+        open: fn(usize) -> Result<bool, rcon::Error>,
+    }
+
+    impl FakeServer {
+        fn new(open: fn(usize) -> Result<bool, rcon::Error>) -> Self {
+            Self {
+                ran: Default::default(),
+                attempts: AtomicUsize::new(0),
+                open,
+            }
+        }
+
+        fn connect(&self) -> impl Future<Output = Result<FakeConnection, rcon::Error>> {
+            let attempt = self.attempts.fetch_add(1, Ordering::SeqCst);
+            let connection = (self.open)(attempt).map(|broken| FakeConnection {
+                ran: self.ran.clone(),
+                broken,
+            });
+            async move { connection }
+        }
+
+        fn attempts(&self) -> usize {
+            self.attempts.load(Ordering::SeqCst)
+        }
+
+        fn ran(&self) -> Vec<String> {
+            self.ran.lock().unwrap().clone()
+        }
+    }
+
+    fn refused() -> rcon::Error {
+        rcon::Error::Io(io::ErrorKind::ConnectionRefused.into())
+    }
+
+    fn incidents() -> IncidentTracker {
+        IncidentTracker::new(Instant::now(), INCIDENT_WINDOW, Duration::ZERO)
+    }
+
+    #[async_std::test]
+    async fn io_error_on_first_attempt_waits_for_the_server() {
+        let dir = TempDir::new();
+        let config = test_config(&dir).await;
+        let server = FakeServer::new(|_| Err(refused()));
+        let (_cmd_send, cmd_rec) = channel::bounded(1);
+        let (contact_send, contact_rec) = channel::bounded(1);
+        let mut session = Session::default();
+
+        let res = RconManager::inner(
+            &|| server.connect(),
+            &config,
+            &cmd_rec,
+            &contact_send,
+            true,
+            None,
+            &mut session,
+        )
+        .await;
+        assert!(res.is_ok());
+        assert!(contact_rec.try_recv().is_err());
+
+        // Afterwards, the server is expected to be reachable
+        let (back, _) = channel::bounded(1);
+        let err = RconManager::inner(
+            &|| server.connect(),
+            &config,
+            &cmd_rec,
+            &contact_send,
+            false,
+            Some(MinecraftCommand::Raw("list".into(), back)),
+            &mut session,
+        )
+        .await
+        .err()
+        .unwrap();
+        assert!(!err.fatal);
+        assert!(matches!(err.cmd, Some(MinecraftCommand::Raw(cmd, _)) if cmd == "list"));
+    }
+
+    #[async_std::test]
+    async fn auth_error_is_fatal() {
+        let dir = TempDir::new();
+        let config = test_config(&dir).await;
+        let server = FakeServer::new(|_| Err(rcon::Error::Auth));
+        let (_cmd_send, cmd_rec) = channel::bounded(1);
+        let (contact_send, _contact_rec) = channel::bounded(1);
+
+        let failure = RconManager::run(
+            &config,
+            || server.connect(),
+            &cmd_rec,
+            &contact_send,
+            incidents(),
+        )
+        .await;
+        assert!(failure.fatal);
+        assert_eq!(server.attempts(), 1);
+    }
+
+    #[async_std::test]
+    async fn pending_message_is_replayed_after_reconnecting() {
+        let dir = TempDir::new();
+        let config = test_config(&dir).await;
+        // The first connection breaks on the first command
+        let server = FakeServer::new(|attempt| Ok(attempt == 0));
+        let (cmd_send, cmd_rec) = channel::bounded(2);
+        let (contact_send, _contact_rec) = channel::bounded(1);
+        let (back_send, back_rec) = channel::bounded(1);
+
+        cmd_send
+            .send(MinecraftCommand::Raw("list".into(), back_send))
+            .await
+            .unwrap();
+        cmd_send.send(MinecraftCommand::SaveOn).await.unwrap();
+        // The manager then fails on the closed channel until it gives up
+        drop(cmd_send);
+
+        RconManager::run(
+            &config,
+            || server.connect(),
+            &cmd_rec,
+            &contact_send,
+            incidents(),
+        )
+        .await;
+        assert_eq!(server.ran(), ["list", "save-on"]);
+        assert_eq!(back_rec.try_recv().unwrap(), "ran list");
+    }
+
+    #[async_std::test]
+    async fn gives_up_after_too_many_incidents() {
+        let dir = TempDir::new();
+        let config = test_config(&dir).await;
+        let server = FakeServer::new(|_| Ok(true));
+        let (cmd_send, cmd_rec) = channel::bounded(1);
+        let (contact_send, contact_rec) = channel::bounded(1);
+        cmd_send.send(MinecraftCommand::SaveOn).await.unwrap();
+
+        let failure = RconManager::run(
+            &config,
+            || server.connect(),
+            &cmd_rec,
+            &contact_send,
+            incidents(),
+        )
+        .await;
+        assert!(!failure.fatal);
+        assert_eq!(
+            failure.err_log,
+            ["[RCON] Too many RCON incidents in a short period of time."]
+        );
+        assert_eq!(server.attempts(), MAX_RECENT_INCIDENTS as usize + 1);
+        assert!(server.ran().is_empty());
+        assert!(contact_rec.try_recv().is_ok());
+    }
+
+    #[async_std::test]
+    async fn address_brackets_ipv6_hosts() {
+        let dir = TempDir::new();
+        let mut config = test_config(&dir).await;
+        config.rcon_port = 25575;
+
+        let cases = [
+            ("127.0.0.1", "127.0.0.1:25575"),
+            ("::1", "[::1]:25575"),
+            ("[::1]", "[::1]:25575"),
+            ("fe80::1", "[fe80::1]:25575"),
+            ("localhost", "localhost:25575"),
+            ("mc.example.com", "mc.example.com:25575"),
+        ];
+        for (host, address) in cases {
+            config.rcon_host = host.into();
+            assert_eq!(RconManager::address(&config), address);
         }
     }
 }
@@ -1,31 +1,104 @@
 use std::{
     ops::{Deref, DerefMut},
-    process::Stdio,
+    os::unix::process::{CommandExt as StdCommandExt, ExitStatusExt},
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+    sync::atomic::{AtomicBool, AtomicI32, Ordering},
+    sync::{Arc, Mutex, OnceLock},
     time::{Duration, Instant},
 };
 
 use crate::{
     backup::BackupManager,
-    config::Config,
-    mail::{MailManager, MailRequest},
-    rcon::RconManager,
+    cmd_utils::Dmesg,
+    config::{Config, ShutdownMethod, ShutdownSignal},
+    control::{ControlServer, SharedStatus, SOCKET_NAME},
+    drain,
+    idle::IdleManager,
+    mail::{format_time, send_report, send_warning, MailManager, ReportChannel},
+    memory::MemoryWatcher,
+    output::{self, OutputBuffer},
+    properties::Properties,
+    rcon::{log_command_response, MinecraftCommand, RconManager},
+    slack::SlackManager,
+    wake::WakeListener,
+    webhook::WebhookManager,
 };
 
 use anyhow::Result;
+use async_std::os::unix::process::CommandExt;
 use async_std::process::{Child, Command};
 use async_std::{
-    channel::{self},
+    channel::{self, Receiver, Sender},
     prelude::FutureExt as AsyncStdFutureExt,
 };
 use chrono::Utc;
 use futures::{pin_mut, select, FutureExt};
-use nix::sys::signal::{self, Signal};
+use nix::{
+    libc,
+    sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal},
+    unistd::{self, Pid},
+};
+
+const LAST_INCIDENT_REPORT: &str = "last_incident_report.txt";
+
+// The server runs in its own process group so that processes it forks can be
+// signaled along with it, which means it no longer receives terminal signals.
+// The manager forwards them instead, and stops after the server exited.
+static SERVER_PGID: AtomicI32 = AtomicI32::new(0);
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+// Wakes the manager when a stop is requested other than by a signal
+static STOP_WAKER: OnceLock<(Sender<()>, Receiver<()>)> = OnceLock::new();
+// Set when the manager stops to run its new executable
+static UPDATE_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+const UPDATE_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+// Stops the server as set by `shutdown_method`, then the manager, which
+// also exits cleanly if no server is running
+pub fn request_stop() {
+    STOP_REQUESTED.store(true, Ordering::SeqCst);
+    stop_waker().0.try_send(()).ok();
+}
+
+fn stop_waker() -> &'static (Sender<()>, Receiver<()>) {
+    STOP_WAKER.get_or_init(|| channel::bounded(1))
+}
+
+// Resolves once a stop was requested through `request_stop`. Signals are
+// forwarded to the server instead, which then exits by itself.
+async fn stop_requested() {
+    stop_waker().1.recv().await.ok();
+}
+
+pub fn update_requested() -> bool {
+    UPDATE_REQUESTED.load(Ordering::SeqCst)
+}
+
+// Replaces the manager with `executable`, run with the same arguments. Only
+// returns if that failed.
+pub fn exec_manager(executable: &Path) -> std::io::Error {
+    std::process::Command::new(executable)
+        .args(std::env::args_os().skip(1))
+        .exec()
+}
+
+extern "C" fn forward_signal(sig: libc::c_int) {
+    STOP_REQUESTED.store(true, Ordering::SeqCst);
+
+    let pgid = SERVER_PGID.load(Ordering::SeqCst);
+    if pgid > 0 {
+        unsafe { libc::kill(-pgid, sig) };
+    } else {
+        unsafe { libc::_exit(128 + sig) };
+    }
+}
 
-pub struct ChildKiller(pub Child);
+pub struct ChildKiller(pub Child, pub Config);
 
 impl Drop for ChildKiller {
     fn drop(&mut self) {
-        async_std::task::block_on(ServerManager::emergency_shutdown(&mut self.0));
+        async_std::task::block_on(ServerManager::emergency_shutdown(&mut self.0, &self.1));
     }
 }
 
@@ -43,163 +116,898 @@ impl DerefMut for ChildKiller {
     }
 }
 
+enum ServerExit {
+    Incident(Vec<String>),
+    // Incidents that restarting the server cannot fix
+    Fatal(Vec<String>),
+    // The server stopped by itself with a success status, as with `/stop`
+    Clean,
+    Idle,
+    Memory,
+}
+
+// Incidents less than this apart are counted as recent, unless the server is
+// known to have been stable in between
+const INCIDENT_WINDOW: Duration = Duration::from_secs(15 * 60);
+pub const MAX_RECENT_INCIDENTS: u32 = 5;
+const RESTART_DELAY: Duration = Duration::from_secs(10);
+
+pub enum Decision {
+    Restart(Duration),
+    Bailout,
+}
+
+// Decides whether to restart after an incident, giving up when incidents keep
+// happening in a short period of time. Also used for RCON reconnections.
+pub struct IncidentTracker {
+    last_incident: Instant,
+    recent_incidents: u32,
+    window: Duration,
+    delay: Duration,
+}
+
+impl IncidentTracker {
+    // Incidents less than `window` apart are counted as recent, and restarts
+    // are delayed by `delay`
+    pub fn new(now: Instant, window: Duration, delay: Duration) -> Self {
+        Self {
+            last_incident: now,
+            recent_incidents: 0,
+            window,
+            delay,
+        }
+    }
+
+    // `stable` tells whether the server ran for long enough before the
+    // incident, if known. Otherwise, the time since the last incident is used.
+    pub fn record_incident(&mut self, now: Instant, stable: Option<bool>) -> Decision {
+        let was_stable =
+            stable.unwrap_or_else(|| now.duration_since(self.last_incident) > self.window);
+        if was_stable {
+            self.recent_incidents = 0;
+        }
+
+        self.recent_incidents += 1;
+        self.last_incident = now;
+
+        if self.recent_incidents > MAX_RECENT_INCIDENTS {
+            Decision::Bailout
+        } else {
+            Decision::Restart(self.delay)
+        }
+    }
+}
+
+// Whether the manager stopped because of the server failing, which
+// supervisors are told through the exit code
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ManagerExit {
+    Stopped,
+    Failed,
+}
+
 pub struct ServerManager;
 
 impl ServerManager {
-    pub async fn start(config: Config) -> Result<()> {
-        let mut last_incident = Instant::now();
-        let mut recent_incidents = 0;
+    pub async fn start(config: Config) -> Result<ManagerExit> {
+        let mut incidents = IncidentTracker::new(Instant::now(), INCIDENT_WINDOW, RESTART_DELAY);
+
+        let handler = SigAction::new(
+            SigHandler::Handler(forward_signal),
+            SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+        unsafe {
+            signal::sigaction(Signal::SIGINT, &handler)?;
+            signal::sigaction(Signal::SIGTERM, &handler)?;
+        }
+
+        let rcon_port = config.rcon_port.to_string();
+        let mut properties: Vec<(&str, &str)> = config
+            .properties_overrides
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+
+        if config.ensure_rcon_enabled {
+            properties.extend([
+                ("enable-rcon", "true"),
+                ("rcon.port", &rcon_port),
+                ("rcon.password", &config.rcon_password),
+            ]);
+        }
+
+        if !properties.is_empty() {
+            let path = config.server_folder.join("server.properties");
+            let created = !path.exists();
+            Properties::update(&path, &properties).inspect_err(|_| {
+                error!("Failed to update server.properties.");
+            })?;
+
+            // The server must still be able to write the file it would have created
+            if let Some(run_as) = config.run_as.as_ref().filter(|_| created) {
+                let uid = run_as.user.as_ref().map(|user| user.uid);
+                unistd::chown(&path, uid, Some(run_as.gid))?;
+            }
+        }
 
         let mail_handles = if let Some(mail_config) = &config.mailing {
-            MailManager::test_mail(mail_config.clone(), &config.name)
-                .await
-                .map_err(|e| {
-                    println!("[ServerManager] [MAIL] Failed to send startup email.");
-                    e
-                })?;
+            if let Err(err) =
+                MailManager::test_mail(mail_config.clone(), &config.name, config.timezone.is_some())
+                    .await
+            {
+                if mail_config.strict_mail {
+                    error!("[MAIL] Failed to verify mail configuration.");
+                    return Err(err);
+                }
+
+                warn!(
+                    "[MAIL] Failed to verify mail configuration, continuing anyway:\n{}",
+                    err
+                );
+            }
+
+            let (snd, rec) = channel::bounded(32);
+
+            let mail_task = async_std::task::spawn(
+                MailManager::start(
+                    mail_config.clone(),
+                    config.name.clone(),
+                    config.timezone.is_some(),
+                    rec,
+                )
+                .inspect(|res| {
+                    // Otherwise only known once the manager exits
+                    if let Err(err) = res {
+                        error!(
+                            "[MAIL] Mail task stopped, incidents are no longer mailed:\n{}",
+                            err
+                        );
+                    }
+                }),
+            );
+
+            Some((mail_task, snd))
+        } else {
+            None
+        };
+
+        let webhook_handles = if let Some(webhook_config) = &config.webhook {
+            WebhookManager::notify_startup(webhook_config, &config.name, config.timezone.is_some())
+                .await;
 
             let (snd, rec) = channel::bounded(32);
 
-            let mail_task = async_std::task::spawn(MailManager::start(
-                mail_config.clone(),
+            let webhook_task = async_std::task::spawn(WebhookManager::start(
+                webhook_config.clone(),
                 config.name.clone(),
+                config.timezone.is_some(),
                 rec,
             ));
 
-            Some((mail_task, snd))
+            Some((webhook_task, snd))
         } else {
             None
         };
 
-        loop {
-            let serv_handle = Command::new(&config.java)
-                .args(&config.java_args)
+        let slack_handles = if let Some(slack_config) = &config.slack {
+            SlackManager::notify_startup(slack_config, &config.name, config.timezone.is_some())
+                .await;
+
+            let (snd, rec) = channel::bounded(32);
+
+            let slack_task = async_std::task::spawn(SlackManager::start(
+                slack_config.clone(),
+                config.name.clone(),
+                config.timezone.is_some(),
+                rec,
+            ));
+
+            Some((slack_task, snd))
+        } else {
+            None
+        };
+
+        let status = SharedStatus::default();
+        let (backup_trigger_send, backup_trigger_rec) = channel::bounded(1);
+
+        let update_watcher = if config.restart_on_update {
+            Some(async_std::task::spawn(Self::watch_executable(
+                std::env::current_exe()?,
+                status.clone(),
+            )))
+        } else {
+            None
+        };
+
+        let control_handle = match ControlServer::bind().await {
+            Ok(Some(listener)) => Some(async_std::task::spawn(ControlServer::serve(
+                listener,
+                config.name.clone(),
+                config.command_profile.clone(),
+                status.clone(),
+                config.backups.as_ref().map(|_| backup_trigger_send.clone()),
+            ))),
+            Ok(None) => {
+                warn!(
+                    "[CONTROL] Another manager is using {}, control commands will not reach this one.",
+                    SOCKET_NAME
+                );
+                None
+            }
+            Err(err) => {
+                warn!("[CONTROL] Failed to open {}:\n{}", SOCKET_NAME, err);
+                None
+            }
+        };
+
+        // Each notifier only gets the reports of the severities it handles
+        let report_chans: Vec<ReportChannel> = mail_handles
+            .iter()
+            .zip(&config.mailing)
+            .map(|((_, chan), mail_config)| ("MAIL", chan.clone(), mail_config.severities.clone()))
+            .chain(webhook_handles.iter().zip(&config.webhook).map(
+                |((_, chan), webhook_config)| {
+                    ("WEBHOOK", chan.clone(), webhook_config.severities.clone())
+                },
+            ))
+            .chain(
+                slack_handles
+                    .iter()
+                    .zip(&config.slack)
+                    .map(|((_, chan), slack_config)| {
+                        ("SLACK", chan.clone(), slack_config.severities.clone())
+                    }),
+            )
+            .collect();
+
+        let manager_exit = loop {
+            if STOP_REQUESTED.load(Ordering::SeqCst) {
+                info!("Stop requested while the server was not running. Exiting.");
+                break ManagerExit::Stopped;
+            }
+
+            let mut cmd = Command::new(&config.java);
+            cmd.args(&config.java_args)
                 .arg("-jar")
                 .arg(&config.server_jar)
                 .arg("nogui")
-                .stdout(Stdio::inherit())
-                .stderr(Stdio::inherit())
                 .stdin(Stdio::inherit())
-                .current_dir(&config.server_folder)
-                .spawn()?;
+                .current_dir(&config.working_dir);
 
-            let mut serv_handle = ChildKiller(serv_handle);
+            if let Some(java_home) = &config.java_home {
+                cmd.env("JAVA_HOME", java_home);
+            }
 
-            let (cmd_send, cmd_rec) = channel::bounded(32);
+            // Output is always captured to recognize fatal startup errors, and
+            // copied to the terminal
+            cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
 
-            let rcon_man = RconManager::start(config.clone(), cmd_rec).fuse();
-            let backup_man = BackupManager::start(config.backups.clone(), cmd_send).fuse();
-            let serv_man = serv_handle.status().fuse();
+            if let Some(user) = config
+                .run_as
+                .as_ref()
+                .and_then(|run_as| run_as.user.as_ref())
+            {
+                cmd.env("HOME", &user.dir).env("USER", &user.name);
+            }
 
-            pin_mut!(rcon_man, backup_man, serv_man);
-
-            let err_log = select! {
-                res = serv_man => {
-                    let mut err_log = vec!["Spontaneous server exit.".into()];
-                    match res {
-                        Err(err) => {
-                            err_log.push(
-                                format!("An error occured while obtaining server exit status:\n{}",
-                                err
-                            ));
-                        }
-                        Ok(status) => {
-                            err_log.push(format!("Status code: {}", status));
+            // Groups are changed first, as they cannot be changed once the user is
+            let run_as = config.run_as.clone();
+            unsafe {
+                cmd.pre_exec(move || {
+                    unistd::setsid()?;
+                    if let Some(run_as) = &run_as {
+                        unistd::setgroups(&run_as.groups)?;
+                        unistd::setgid(run_as.gid)?;
+                        if let Some(user) = &run_as.user {
+                            unistd::setuid(user.uid)?;
                         }
                     }
+                    Ok(())
+                });
+            }
 
-                    err_log
-                }
-                mut err_log = rcon_man => {
-                    Self::emergency_shutdown(&mut serv_handle).await;
+            let mut serv_handle = cmd.spawn()?;
+            SERVER_PGID.store(serv_handle.id() as i32, Ordering::SeqCst);
+
+            if let Ok(mut status) = status.lock() {
+                status.server_pid = Some(serv_handle.id());
+                status.server_started = Some(Utc::now());
+            }
+
+            // A new buffer for every run, so that a fatal error seen in a
+            // previous run is not reported again
+            let (ready_send, ready_rec) = channel::bounded(1);
+            let output = Arc::new(Mutex::new(OutputBuffer::new(
+                config.incident_log_lines,
+                config
+                    .ready_pattern
+                    .clone()
+                    .map(|pattern| (pattern, ready_send)),
+            )));
+            let mut capture_tasks = Vec::new();
+            if let Some(stdout) = serv_handle.stdout.take() {
+                capture_tasks.push(async_std::task::spawn(output::capture(
+                    stdout,
+                    async_std::io::stdout(),
+                    output.clone(),
+                )));
+            }
+            if let Some(stderr) = serv_handle.stderr.take() {
+                capture_tasks.push(async_std::task::spawn(output::capture(
+                    stderr,
+                    async_std::io::stderr(),
+                    output.clone(),
+                )));
+            }
+
+            let mut serv_handle = ChildKiller(serv_handle, config.clone());
+
+            let (cmd_send, cmd_rec) = channel::bounded(config.command_queue_size);
+            if let Ok(mut status) = status.lock() {
+                status.server_commands = Some(cmd_send.clone());
+            }
+
+            let run_start = Instant::now();
+            let server_pid = serv_handle.id();
+            let (contact_send, contact_rec) = channel::bounded(1);
+
+            let ready_task = config.ready_pattern.as_ref().map(|_| {
+                async_std::task::spawn(Self::on_ready(
+                    config.clone(),
+                    ready_rec,
+                    cmd_send.clone(),
+                    status.clone(),
+                ))
+            });
+
+            let rcon_man = RconManager::start(config.clone(), cmd_rec, contact_send).fuse();
+            let backup_man = BackupManager::start(
+                config.backups.clone(),
+                cmd_send.clone(),
+                backup_trigger_rec.clone(),
+                status.clone(),
+                report_chans.clone(),
+            )
+            .fuse();
+            let idle_man = IdleManager::start(
+                config.idle_shutdown,
+                config.shutdown_method != ShutdownMethod::Signal,
+                config.command_profile.clone(),
+                cmd_send.clone(),
+            )
+            .fuse();
+            let memory_man = MemoryWatcher::start(config.clone(), server_pid, cmd_send).fuse();
+            let serv_man = serv_handle.status().fuse();
+            let stop_man = stop_requested().fuse();
+
+            pin_mut!(rcon_man, backup_man, idle_man, memory_man, serv_man, stop_man);
+
+            let exit = select! {
+                res = serv_man => match res {
+                    Ok(status) if status.success() && !config.restart_on_clean_exit => {
+                        ServerExit::Clean
+                    }
+                    Err(err) => ServerExit::Incident(vec![
+                        "Spontaneous server exit.".into(),
+                        format!("An error occured while obtaining server exit status:\n{}", err),
+                    ]),
+                    Ok(status) => ServerExit::Incident(vec![
+                        "Spontaneous server exit.".into(),
+                        Self::describe_exit(status, server_pid).await,
+                    ]),
+                },
+                failure = rcon_man => {
+                    Self::emergency_shutdown(&mut serv_handle, &config).await;
+                    let mut err_log = failure.err_log;
                     err_log.push("Emergency server shutdown caused by RCON failure.".into());
-                    err_log
+                    if failure.fatal {
+                        ServerExit::Fatal(err_log)
+                    } else {
+                        ServerExit::Incident(err_log)
+                    }
                 }
                 mut err_log = backup_man => {
-                    Self::emergency_shutdown(&mut serv_handle).await;
+                    Self::emergency_shutdown(&mut serv_handle, &config).await;
                     err_log.push("Emergency server shutdown caused by backup failure.".into());
-                    err_log
+                    ServerExit::Incident(err_log)
+                }
+                _ = idle_man => {
+                    let stopped = config.shutdown_method != ShutdownMethod::Signal
+                        && serv_handle.status().timeout(Duration::from_secs(60)).await.is_ok();
+                    if !stopped {
+                        Self::emergency_shutdown(&mut serv_handle, &config).await;
+                    }
+                    ServerExit::Idle
+                }
+                _ = memory_man => {
+                    let stopped = config.shutdown_method != ShutdownMethod::Signal
+                        && serv_handle.status().timeout(Duration::from_secs(60)).await.is_ok();
+                    if !stopped {
+                        Self::emergency_shutdown(&mut serv_handle, &config).await;
+                    }
+                    ServerExit::Memory
+                }
+                _ = stop_man => {
+                    Self::emergency_shutdown(&mut serv_handle, &config).await;
+                    ServerExit::Clean
                 }
             };
 
-            for e in &err_log {
-                println!("[ServerManager] {}", e);
+            SERVER_PGID.store(0, Ordering::SeqCst);
+            drain::restore_whitelist(&config.server_folder);
+
+            if let Some(task) = ready_task {
+                task.cancel().await;
+            }
+
+            let mut ready = false;
+            if let Ok(mut status) = status.lock() {
+                status.server_pid = None;
+                status.server_started = None;
+                status.server_commands = None;
+                status.seed = None;
+                ready = std::mem::take(&mut status.server_ready);
             }
 
-            println!("[ServerManager] The server exited.");
+            if update_requested() {
+                info!("The server was stopped to restart the manager.");
+                break ManagerExit::Stopped;
+            }
 
-            if config.auto_restart {
-                if (Instant::now() - last_incident) > Duration::from_secs(15 * 60) {
-                    recent_incidents = 0;
-                }
+            if STOP_REQUESTED.load(Ordering::SeqCst) {
+                info!("The server was stopped on request. Exiting.");
+                break ManagerExit::Stopped;
+            }
 
-                recent_incidents += 1;
+            let exit = match exit {
+                ServerExit::Incident(mut err_log) => {
+                    // Lets the last lines of output reach the buffer
+                    for task in capture_tasks {
+                        task.timeout(Duration::from_secs(2)).await.ok();
+                    }
 
-                if recent_incidents > 5 {
-                    println!(
-                        "[ServerManager] Too many incidents in a short period of time. Exiting."
-                    );
+                    let (lines, fatal_error) = output
+                        .lock()
+                        .map(|o| (o.lines(), o.fatal_error()))
+                        .unwrap_or_default();
+                    if let Some(fatal_error) = fatal_error {
+                        err_log.push(fatal_error.into());
+                    }
+                    if !lines.is_empty() {
+                        err_log.push("Last server output:".into());
+                        err_log.extend(lines);
+                    }
+
+                    if fatal_error.is_some() {
+                        ServerExit::Fatal(err_log)
+                    } else {
+                        ServerExit::Incident(err_log)
+                    }
+                }
+                exit => exit,
+            };
 
-                    if let Some((_, chan)) = &mail_handles {
-                        chan.send(MailRequest {
-                            err_log,
-                            final_incident: true,
-                            time: Utc::now(),
-                        })
-                        .await
-                        .ok();
+            let err_log = match exit {
+                ServerExit::Incident(err_log) => err_log,
+                ServerExit::Fatal(err_log) => {
+                    for e in &err_log {
+                        error!("{}", e);
                     }
 
-                    break;
+                    error!("Restarting the server cannot fix this incident. Exiting.");
+                    Self::report(&config, &report_chans, err_log, true).await;
+                    break ManagerExit::Failed;
+                }
+                ServerExit::Clean => {
+                    info!("The server stopped cleanly. Exiting.");
+                    break ManagerExit::Stopped;
+                }
+                ServerExit::Idle if config.wake_on_connect => {
+                    info!("The server was stopped for being idle.");
+                    let woken = WakeListener::wait(&config.server_folder, &config.wake_motd)
+                        .map(Some)
+                        .race(stop_requested().map(|_| None))
+                        .await;
+                    if woken.transpose()?.is_some() {
+                        info!("Starting server...");
+                    }
+                    continue;
+                }
+                ServerExit::Idle if config.restart_after_idle => {
+                    info!("The server was stopped for being idle. Restarting.");
+                    continue;
+                }
+                ServerExit::Idle => {
+                    info!("The server was stopped for being idle. Exiting.");
+                    break ManagerExit::Stopped;
+                }
+                ServerExit::Memory => {
+                    info!("The server was stopped for using too much memory. Restarting.");
+                    continue;
+                }
+            };
+
+            for e in &err_log {
+                error!("{}", e);
+            }
+
+            warn!("The server exited.");
+
+            if config.auto_restart {
+                // The server must have started, as told by the ready pattern if
+                // any, or otherwise by RCON being reachable
+                let started = if config.ready_pattern.is_some() {
+                    ready
                 } else {
-                    if let Some((_, chan)) = &mail_handles {
-                        chan.send(MailRequest {
-                            err_log,
-                            final_incident: false,
-                            time: Utc::now(),
-                        })
-                        .await
-                        .ok();
+                    contact_rec.try_recv().is_ok()
+                };
+                let stable = config
+                    .stable_after
+                    .map(|stable_after| started && run_start.elapsed() >= stable_after);
+
+                match incidents.record_incident(Instant::now(), stable) {
+                    Decision::Bailout => {
+                        error!("Too many incidents in a short period of time. Exiting.");
+                        Self::report(&config, &report_chans, err_log, true).await;
+                        break ManagerExit::Failed;
                     }
+                    Decision::Restart(delay) => {
+                        Self::report(&config, &report_chans, err_log, false).await;
+
+                        // Only sent once per series of incidents, as the count
+                        // goes past the threshold afterwards
+                        if config.warn_incidents > 0
+                            && incidents.recent_incidents == config.warn_incidents
+                        {
+                            let remaining = MAX_RECENT_INCIDENTS + 1 - incidents.recent_incidents;
+                            let message = format!(
+                                "{} incidents happened in a short period of time. The server manager will stop after {} more.",
+                                incidents.recent_incidents, remaining
+                            );
+                            warn!("{}", message);
+                            send_warning(&report_chans, message, Utc::now()).await;
+                        }
 
-                    last_incident = Instant::now();
-                    println!("[ServerManager] Restarting in 10 seconds...");
-                    async_std::task::sleep(Duration::from_secs(10)).await;
+                        info!("Restarting in {} seconds...", delay.as_secs());
+                        async_std::task::sleep(delay).race(stop_requested()).await;
+                    }
                 }
             } else {
-                println!("[ServerManager] Auto-restart is disabled. Exiting.");
-                if let Some((_, chan)) = &mail_handles {
-                    chan.send(MailRequest {
-                        err_log,
-                        final_incident: true,
-                        time: Utc::now(),
-                    })
-                    .await
-                    .ok();
-                }
-                break;
+                info!("Auto-restart is disabled. Exiting.");
+                Self::report(&config, &report_chans, err_log, true).await;
+                break ManagerExit::Failed;
             }
+        };
+
+        if let Some(handle) = update_watcher {
+            handle.cancel().await;
+        }
+
+        if let Some(handle) = control_handle {
+            handle.cancel().await;
+            std::fs::remove_file(SOCKET_NAME).ok();
+        }
+
+        // Lets the notification tasks finish once they sent pending reports
+        drop(report_chans);
+        if let Some((handle, chan)) = webhook_handles {
+            drop(chan);
+            handle.await;
+        }
+
+        if let Some((handle, chan)) = slack_handles {
+            drop(chan);
+            handle.await;
         }
 
-        if let Some((handle, _)) = mail_handles {
+        if let Some((handle, chan)) = mail_handles {
+            drop(chan);
             handle.await?;
         }
 
-        Ok(())
+        Ok(manager_exit)
+    }
+
+    // Stops the server once the manager executable was replaced and no backup
+    // is running, for the manager to run the new one.
+    async fn watch_executable(executable: PathBuf, status: SharedStatus) {
+        let modified = || {
+            std::fs::metadata(&executable)
+                .and_then(|m| m.modified())
+                .ok()
+        };
+        let mut initial = modified();
+        let mut last = initial;
+        loop {
+            async_std::task::sleep(UPDATE_POLL_INTERVAL).await;
+
+            // Unchanged since the last poll, so that it is fully written
+            let current = modified();
+            if current.is_none() || current == initial || current != last {
+                last = current;
+                continue;
+            }
+
+            info!("The manager executable changed, restarting once no backup is running.");
+            let paused = status.lock().ok().map(|mut status| {
+                let paused = (status.backups_paused, status.backups_resume_at);
+                status.backups_paused = true;
+                status.backups_resume_at = None;
+                paused
+            });
+
+            while status.lock().is_ok_and(|status| status.backup_running) {
+                async_std::task::sleep(Duration::from_secs(1)).await;
+            }
+
+            UPDATE_REQUESTED.store(true, Ordering::SeqCst);
+            if SERVER_PGID.load(Ordering::SeqCst) > 0 {
+                request_stop();
+                return;
+            }
+
+            // Nothing to stop, such as while waiting to restart the server
+            info!("Restarting the manager...");
+            let err = exec_manager(&executable);
+            error!("Failed to restart the manager:\n{}", err);
+            UPDATE_REQUESTED.store(false, Ordering::SeqCst);
+
+            // Backups go on as before, until the executable changes again
+            if let (Some((backups_paused, backups_resume_at)), Ok(mut status)) =
+                (paused, status.lock())
+            {
+                status.backups_paused = backups_paused;
+                status.backups_resume_at = backups_resume_at;
+            }
+            initial = current;
+        }
+    }
+
+    // Waits for the server to print the ready pattern, then does what would
+    // otherwise be done on first RCON contact.
+    async fn on_ready(
+        config: Config,
+        ready_rec: Receiver<()>,
+        cmd_chan: Sender<MinecraftCommand>,
+        status: SharedStatus,
+    ) {
+        if ready_rec.recv().await.is_err() {
+            return;
+        }
+
+        info!("The server is ready.");
+        if let Ok(mut status) = status.lock() {
+            status.server_ready = true;
+        }
+
+        for command in &config.on_connect_commands {
+            let (resp_send, resp_rec) = channel::bounded(1);
+            let sent = cmd_chan
+                .send(MinecraftCommand::Raw(command.clone(), resp_send))
+                .timeout(Duration::from_secs(10))
+                .await;
+
+            let response = match sent {
+                Ok(Ok(())) => resp_rec.recv().timeout(Duration::from_secs(60)).await,
+                _ => {
+                    warn!(
+                        "[RCON] Failed to run `{}`: command queue unavailable.",
+                        command
+                    );
+                    continue;
+                }
+            };
+
+            match response {
+                Ok(Ok(response)) => log_command_response(command, &response),
+                _ => warn!("[RCON] Failed to run `{}`: no response.", command),
+            }
+        }
+
+        let local_time = config.timezone.is_some();
+        if let Some(webhook_config) = &config.webhook {
+            WebhookManager::notify_ready(webhook_config, &config.name, local_time).await;
+        }
+
+        if let Some(slack_config) = &config.slack {
+            SlackManager::notify_ready(slack_config, &config.name, local_time).await;
+        }
     }
 
-    pub async fn emergency_shutdown(serv_handle: &mut Child) {
-        let pid = nix::unistd::Pid::from_raw(serv_handle.id() as i32);
+    async fn report(
+        config: &Config,
+        chans: &[ReportChannel],
+        err_log: Vec<String>,
+        final_incident: bool,
+    ) {
+        let time = Utc::now();
+
+        // Notifications of the final incident may all fail, so it is also
+        // kept in the server folder where it can be found when noticing the
+        // server is down
+        if final_incident {
+            let report = format!(
+                "On {}, the Minecraft server \"{}\" encountered an incident.\n\nError report:\n{}\n\nAfter this incident, the server manager stopped.\n",
+                format_time(time, config.timezone.is_some()),
+                config.name,
+                err_log.join("\n")
+            );
+
+            let path = config.server_folder.join(LAST_INCIDENT_REPORT);
+            if let Err(err) = std::fs::write(path, report) {
+                error!("Failed to write {}:\n{}", LAST_INCIDENT_REPORT, err);
+            }
+        }
+
+        send_report(chans, err_log, final_incident, time).await;
+    }
+
+    async fn describe_exit(status: ExitStatus, pid: u32) -> String {
+        if let Some(code) = status.code() {
+            return match code {
+                0 => "The server exited normally (status code 0).".into(),
+                // Exit status of a wrapper script whose server got killed
+                137 => "The server exited with status code 137, it was likely killed by SIGKILL, possibly by the out-of-memory killer.".into(),
+                code => format!("The server exited with status code {}.", code),
+            };
+        }
+
+        let signal = match status.signal() {
+            Some(signal) => signal,
+            None => return format!("The server exited with status {}.", status),
+        };
+
+        let name = Signal::try_from(signal)
+            .map(|s| s.as_str().to_string())
+            .unwrap_or_else(|_| format!("signal {}", signal));
+
+        let mut res = format!("The server was terminated by {}", name);
+        if status.core_dumped() {
+            res += " and dumped core";
+        }
+        res += ".";
+
+        if signal == libc::SIGKILL {
+            res += match Dmesg::was_oom_killed(pid).await {
+                Some(true) => " It was killed by the out-of-memory killer.",
+                Some(false) => "",
+                None => " It may have been killed by the out-of-memory killer.",
+            };
+        }
+
+        res
+    }
+
+    pub async fn emergency_shutdown(serv_handle: &mut Child, config: &Config) {
+        let pgid = Pid::from_raw(-(serv_handle.id() as i32));
+
+        let mut exited = matches!(serv_handle.try_status(), Ok(Some(_)));
+
+        if !exited && config.shutdown_method != ShutdownMethod::Signal {
+            exited = RconManager::stop(config).await.is_ok()
+                && serv_handle
+                    .status()
+                    .timeout(config.shutdown_timeout)
+                    .await
+                    .is_ok();
+        } else if !exited {
+            RconManager::announce_shutdown(config).await.ok();
+        }
+
+        // With RconStop, the server is only killed as a last resort
+        if !exited && config.shutdown_method != ShutdownMethod::RconStop {
+            let signal = match config.shutdown_signal {
+                ShutdownSignal::Sigint => Signal::SIGINT,
+                ShutdownSignal::Sigterm => Signal::SIGTERM,
+            };
+
+            signal::kill(pgid, signal).ok();
+            exited = serv_handle
+                .status()
+                .timeout(config.shutdown_timeout)
+                .await
+                .is_ok();
+        }
 
-        signal::kill(pid, Signal::SIGINT).ok();
-        if serv_handle
-            .status()
-            .timeout(Duration::from_secs(20))
-            .await
-            .is_err()
-        {
-            signal::kill(pid, Signal::SIGKILL).ok();
+        if !exited {
+            signal::kill(pgid, Signal::SIGKILL).ok();
             serv_handle.status().await.ok();
         }
+
+        // Processes forked by the server may have outlived it
+        signal::kill(pgid, Signal::SIGKILL).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(60);
+    const DELAY: Duration = Duration::from_secs(10);
+
+    fn restarts(decision: Decision) -> bool {
+        matches!(decision, Decision::Restart(delay) if delay == DELAY)
+    }
+
+    #[test]
+    fn bails_out_after_too_many_recent_incidents() {
+        let start = Instant::now();
+        let mut incidents = IncidentTracker::new(start, WINDOW, DELAY);
+
+        for i in 1..=MAX_RECENT_INCIDENTS {
+            let now = start + Duration::from_secs(i as u64);
+            assert!(restarts(incidents.record_incident(now, None)));
+            assert_eq!(incidents.recent_incidents, i);
+        }
+
+        let now = start + Duration::from_secs(30);
+        assert!(matches!(
+            incidents.record_incident(now, None),
+            Decision::Bailout
+        ));
+    }
+
+    #[test]
+    fn counts_incidents_inside_the_window() {
+        let start = Instant::now();
+        let mut incidents = IncidentTracker::new(start, WINDOW, DELAY);
+
+        // Each incident is less than the window after the previous one, even
+        // though they span more than the window in total
+        let mut now = start;
+        for _ in 0..MAX_RECENT_INCIDENTS {
+            now += WINDOW - Duration::from_secs(1);
+            assert!(restarts(incidents.record_incident(now, None)));
+        }
+        assert!(matches!(
+            incidents.record_incident(now + WINDOW, None),
+            Decision::Bailout
+        ));
+    }
+
+    #[test]
+    fn incidents_expire_outside_the_window() {
+        let start = Instant::now();
+        let mut incidents = IncidentTracker::new(start, WINDOW, DELAY);
+
+        let mut now = start;
+        for _ in 0..MAX_RECENT_INCIDENTS * 2 {
+            now += WINDOW + Duration::from_secs(1);
+            assert!(restarts(incidents.record_incident(now, None)));
+            assert_eq!(incidents.recent_incidents, 1);
+        }
+    }
+
+    #[test]
+    fn stable_run_resets_the_count() {
+        let start = Instant::now();
+        let mut incidents = IncidentTracker::new(start, WINDOW, DELAY);
+
+        let now = start + Duration::from_secs(1);
+        for _ in 0..MAX_RECENT_INCIDENTS {
+            incidents.record_incident(now, Some(false));
+        }
+
+        // Known stability wins over the time since the last incident
+        assert!(restarts(incidents.record_incident(now, Some(true))));
+        assert_eq!(incidents.recent_incidents, 1);
+    }
+
+    #[test]
+    fn unstable_run_counts_outside_the_window() {
+        let start = Instant::now();
+        let mut incidents = IncidentTracker::new(start, WINDOW, DELAY);
+
+        let mut now = start;
+        for _ in 0..MAX_RECENT_INCIDENTS {
+            now += WINDOW * 2;
+            assert!(restarts(incidents.record_incident(now, Some(false))));
+        }
+        assert!(matches!(
+            incidents.record_incident(now + WINDOW * 2, Some(false)),
+            Decision::Bailout
+        ));
     }
 }
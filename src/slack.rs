@@ -0,0 +1,203 @@
+use std::{fmt::Write, time::Duration};
+
+use anyhow::Result;
+use async_std::channel::Receiver;
+use chrono::Utc;
+
+use crate::{
+    cmd_utils::Curl,
+    config::SlackConfig,
+    mail::{format_time, MailRequest},
+    rcon::json_string,
+    webhook::Severity,
+};
+
+// Slack rejects section texts longer than 3000 characters
+const MAX_SECTION_LEN: usize = 2900;
+
+pub struct SlackManager;
+
+impl SlackManager {
+    pub async fn notify_startup(config: &SlackConfig, name: &str, local_time: bool) {
+        if !config.send_startup {
+            return;
+        }
+
+        let subject = format!("{} - Minecraft Server Manager Started", name);
+        let summary = format!(
+            "On {}, the Minecraft server manager for \"{}\" started.",
+            format_time(Utc::now(), local_time),
+            escape(name)
+        );
+
+        if let Err(err) = Self::send(config, &subject, &summary, None, Severity::Info).await {
+            error!("[SLACK] Failed to send startup notification:\n{}", err);
+        }
+    }
+
+    pub async fn notify_ready(config: &SlackConfig, name: &str, local_time: bool) {
+        if !config.send_ready {
+            return;
+        }
+
+        let subject = format!("{} - Minecraft Server Ready", name);
+        let summary = format!(
+            "On {}, the Minecraft server \"{}\" finished starting.",
+            format_time(Utc::now(), local_time),
+            escape(name)
+        );
+
+        if let Err(err) = Self::send(config, &subject, &summary, None, Severity::Info).await {
+            error!("[SLACK] Failed to send ready notification:\n{}", err);
+        }
+    }
+
+    pub async fn start(
+        config: SlackConfig,
+        name: String,
+        local_time: bool,
+        slack_rec: Receiver<MailRequest>,
+    ) {
+        while let Ok(request) = slack_rec.recv().await {
+            if request.warning {
+                let subject = format!("{} - Server Manager warning", name);
+                let summary = format!(
+                    "On {}, the Minecraft server manager for \"{}\" raised a warning.\n{}",
+                    format_time(request.time, local_time),
+                    escape(&name),
+                    escape(&request.err_log.join("\n"))
+                );
+
+                if let Err(err) =
+                    Self::send(&config, &subject, &summary, None, Severity::Warning).await
+                {
+                    error!("[SLACK] Failed to send warning notification:\n{}", err);
+                }
+                continue;
+            }
+
+            let (subject, severity) = if request.final_incident {
+                (
+                    format!("URGENT - {} - Server Manager stopped after incident", name),
+                    Severity::Critical,
+                )
+            } else {
+                (format!("{} - Incident report", name), Severity::Warning)
+            };
+
+            let mut summary = format!(
+                "On {}, the Minecraft server \"{}\" encountered an incident.",
+                format_time(request.time, local_time),
+                escape(&name)
+            );
+
+            if request.final_incident {
+                summary += "\n*After this incident, the server manager stopped.*";
+            }
+
+            let report = request.err_log.join("\n");
+            if let Err(err) = Self::send(&config, &subject, &summary, Some(&report), severity).await
+            {
+                error!("[SLACK] Failed to send incident notification:\n{}", err);
+            }
+
+            if request.final_incident {
+                break;
+            }
+        }
+    }
+
+    // The summary is shown in the message itself, while the report goes in a
+    // colored attachment that Slack collapses when it is long.
+    async fn send(
+        config: &SlackConfig,
+        subject: &str,
+        summary: &str,
+        report: Option<&str>,
+        severity: Severity,
+    ) -> Result<()> {
+        let mut payload = String::from("{");
+        if let Some(channel) = &config.channel {
+            write!(payload, "\"channel\":{},", json_string(channel))?;
+        }
+
+        write!(
+            payload,
+            "\"text\":{},\"blocks\":[{},{}]",
+            json_string(subject),
+            header_block(subject),
+            section_block(summary)
+        )?;
+
+        let color = match severity {
+            Severity::Info => "#2eb886",
+            Severity::Warning => "#daa038",
+            Severity::Critical => "#a30200",
+        };
+        let details = match report {
+            Some(report) => format!(
+                "{},{}",
+                section_block("*Error report:*"),
+                section_block(&code_block(report))
+            ),
+            None => section_block(&format!("Severity: {}", severity.as_str())),
+        };
+        write!(
+            payload,
+            ",\"attachments\":[{{\"color\":\"{}\",\"blocks\":[{}]}}]}}",
+            color, details
+        )?;
+
+        let headers = [("Content-Type".into(), "application/json".into())];
+
+        let mut attempts = 0;
+        loop {
+            match Curl::request("POST", config.url.as_str(), &headers, &payload).await {
+                Ok(()) => return Ok(()),
+                Err(err) if attempts >= 4 => return Err(err),
+                Err(_) => {
+                    async_std::task::sleep(Duration::from_secs(2u64.pow(attempts + 1))).await;
+                    attempts += 1;
+                }
+            }
+        }
+    }
+}
+
+fn header_block(text: &str) -> String {
+    // Headers are limited to 150 characters
+    let text: String = text.chars().take(150).collect();
+    format!(
+        "{{\"type\":\"header\",\"text\":{{\"type\":\"plain_text\",\"text\":{}}}}}",
+        json_string(&text)
+    )
+}
+
+fn section_block(text: &str) -> String {
+    format!(
+        "{{\"type\":\"section\",\"text\":{{\"type\":\"mrkdwn\",\"text\":{}}}}}",
+        json_string(text)
+    )
+}
+
+// Keeps the end of long reports, where the cause of the incident usually is
+fn code_block(report: &str) -> String {
+    let report = escape(report).replace("```", "'''");
+    let report = if report.len() > MAX_SECTION_LEN {
+        let mut start = report.len() - MAX_SECTION_LEN;
+        while !report.is_char_boundary(start) {
+            start += 1;
+        }
+        format!("...\n{}", &report[start..])
+    } else {
+        report
+    };
+    format!("```{}```", report)
+}
+
+// Slack interprets these characters as markup
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
@@ -0,0 +1,73 @@
+use std::{
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Once,
+    },
+};
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+static FAKE_TOOLS: Once = Once::new();
+
+// Tools that configs check for, standing in for the real ones
+const FAKE_TOOL_NAMES: [&str; 5] = ["duplicity", "rclone", "gpg", "btrfs", "zfs"];
+
+// A folder removed along with its content once dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "server-manager-test-{}-{}",
+            std::process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(std::fs::canonicalize(path).unwrap())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn create_dir(&self, name: &str) -> PathBuf {
+        let path = self.0.join(name);
+        std::fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    pub fn write(&self, name: &str, content: &str) -> PathBuf {
+        let path = self.0.join(name);
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+// Puts scripts that always succeed in front of PATH, once for all tests so
+// that no test sees PATH change while spawning a tool
+pub fn fake_tools() {
+    FAKE_TOOLS.call_once(|| {
+        // Shared by test runs, so that it is not left behind by each of them
+        let bin = std::env::temp_dir().join("server-manager-test-bin");
+        std::fs::create_dir_all(&bin).unwrap();
+
+        for name in FAKE_TOOL_NAMES {
+            let path = bin.join(name);
+            if !path.is_file() {
+                std::fs::write(&path, "#!/bin/sh\nexit 0\n").unwrap();
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+            }
+        }
+
+        let path = std::env::var_os("PATH").unwrap_or_default();
+        let paths = std::iter::once(bin).chain(std::env::split_paths(&path));
+        std::env::set_var("PATH", std::env::join_paths(paths).unwrap());
+    });
+}
@@ -0,0 +1,189 @@
+use std::{path::Path, time::Duration};
+
+use anyhow::{anyhow, Result};
+use async_std::{
+    io::{ReadExt, WriteExt},
+    net::{TcpListener, TcpStream},
+    prelude::FutureExt as AsyncStdFutureExt,
+};
+
+use crate::{properties::Properties, rcon::json_string};
+
+const MAX_PACKET_SIZE: usize = 32 * 1024;
+
+pub struct WakeListener;
+
+impl WakeListener {
+    // Answers server list pings on the Minecraft port until a player tries to join.
+    pub async fn wait(server_folder: &Path, motd: &str) -> Result<()> {
+        let (ip, port) = match Properties::load(&server_folder.join("server.properties")) {
+            Ok(properties) => (
+                properties
+                    .get("server-ip")
+                    .filter(|ip| !ip.is_empty())
+                    .unwrap_or("0.0.0.0")
+                    .to_string(),
+                properties
+                    .get("server-port")
+                    .and_then(|port| port.parse().ok())
+                    .unwrap_or(25565),
+            ),
+            Err(_) => ("0.0.0.0".into(), 25565),
+        };
+
+        let listener = TcpListener::bind((ip.as_str(), port)).await?;
+        info!("[WAKE] Waiting for a player to connect on port {}...", port);
+
+        loop {
+            let (mut stream, _) = listener.accept().await?;
+            if let Ok(Ok(true)) = Self::handle(&mut stream, motd)
+                .timeout(Duration::from_secs(10))
+                .await
+            {
+                info!("[WAKE] A player tried to join.");
+                return Ok(());
+            }
+        }
+    }
+
+    // Returns whether the client attempted to join the server.
+    async fn handle(stream: &mut TcpStream, motd: &str) -> Result<bool> {
+        let (id, data) = read_packet(stream).await?;
+        if id != 0x00 {
+            return Ok(false);
+        }
+
+        // Handshake
+        let mut data = data.as_slice();
+        let protocol = take_varint(&mut data)?;
+        take_string(&mut data)?;
+        take_bytes(&mut data, 2)?;
+        let next_state = take_varint(&mut data)?;
+
+        match next_state {
+            1 => {
+                let (id, _) = read_packet(stream).await?;
+                if id != 0x00 {
+                    return Ok(false);
+                }
+
+                let status = format!(
+                    "{{\"version\":{{\"name\":\"Sleeping\",\"protocol\":{}}},\"players\":{{\"max\":0,\"online\":0}},\"description\":{{\"text\":{}}}}}",
+                    protocol,
+                    json_string(motd)
+                );
+                let mut response = Vec::new();
+                write_string(&mut response, &status);
+                write_packet(stream, 0x00, &response).await?;
+
+                let (id, payload) = read_packet(stream).await?;
+                if id == 0x01 {
+                    write_packet(stream, 0x01, &payload).await?;
+                }
+
+                Ok(false)
+            }
+            2 | 3 => {
+                let mut response = Vec::new();
+                write_string(
+                    &mut response,
+                    &format!(
+                        "{{\"text\":{}}}",
+                        json_string("The server is starting, please reconnect in a minute.")
+                    ),
+                );
+                write_packet(stream, 0x00, &response).await.ok();
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+async fn read_varint(stream: &mut TcpStream) -> Result<i32> {
+    let mut value = 0u32;
+    for i in 0..5 {
+        let mut byte = [0u8];
+        stream.read_exact(&mut byte).await?;
+        value |= ((byte[0] & 0x7f) as u32) << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            return Ok(value as i32);
+        }
+    }
+    Err(anyhow!("varint is too long"))
+}
+
+async fn read_packet(stream: &mut TcpStream) -> Result<(i32, Vec<u8>)> {
+    let len = read_varint(stream).await?;
+    if len <= 0 || len as usize > MAX_PACKET_SIZE {
+        return Err(anyhow!("invalid packet length {}", len));
+    }
+
+    let mut data = vec![0u8; len as usize];
+    stream.read_exact(&mut data).await?;
+
+    let mut slice = data.as_slice();
+    let id = take_varint(&mut slice)?;
+    Ok((id, slice.to_vec()))
+}
+
+async fn write_packet(stream: &mut TcpStream, id: i32, payload: &[u8]) -> Result<()> {
+    let mut body = Vec::with_capacity(payload.len() + 5);
+    write_varint(&mut body, id);
+    body.extend_from_slice(payload);
+
+    let mut packet = Vec::with_capacity(body.len() + 5);
+    write_varint(&mut packet, body.len() as i32);
+    packet.extend_from_slice(&body);
+
+    stream.write_all(&packet).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+fn take_bytes<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if data.len() < len {
+        return Err(anyhow!("packet is too short"));
+    }
+    let (taken, rest) = data.split_at(len);
+    *data = rest;
+    Ok(taken)
+}
+
+fn take_varint(data: &mut &[u8]) -> Result<i32> {
+    let mut value = 0u32;
+    for i in 0..5 {
+        let byte = take_bytes(data, 1)?[0];
+        value |= ((byte & 0x7f) as u32) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(value as i32);
+        }
+    }
+    Err(anyhow!("varint is too long"))
+}
+
+fn take_string(data: &mut &[u8]) -> Result<String> {
+    let len = take_varint(data)?;
+    if len < 0 {
+        return Err(anyhow!("invalid string length {}", len));
+    }
+    let bytes = take_bytes(data, len as usize)?;
+    Ok(String::from_utf8_lossy(bytes).into_owned())
+}
+
+fn write_varint(buf: &mut Vec<u8>, value: i32) {
+    let mut value = value as u32;
+    loop {
+        if value & !0x7f == 0 {
+            buf.push(value as u8);
+            return;
+        }
+        buf.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+}
+
+fn write_string(buf: &mut Vec<u8>, text: &str) {
+    write_varint(buf, text.len() as i32);
+    buf.extend_from_slice(text.as_bytes());
+}
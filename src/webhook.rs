@@ -0,0 +1,192 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use async_std::channel::Receiver;
+use chrono::Utc;
+
+use crate::{
+    cmd_utils::Curl,
+    config::{WebhookConfig, WebhookContentType},
+    mail::{format_time, MailRequest},
+    rcon::json_string,
+};
+
+#[derive(Clone, Copy)]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+impl Severity {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        }
+    }
+}
+
+pub struct WebhookManager;
+
+impl WebhookManager {
+    pub async fn notify_startup(config: &WebhookConfig, name: &str, local_time: bool) {
+        if !config.send_startup {
+            return;
+        }
+
+        let subject = format!("{} - Minecraft Server Manager Started", name);
+        let body = format!(
+            "On {}, the Minecraft server manager for \"{}\" started.",
+            format_time(Utc::now(), local_time),
+            name
+        );
+
+        if let Err(err) = Self::send(config, &subject, &body, Severity::Info).await {
+            error!("[WEBHOOK] Failed to send startup notification:\n{}", err);
+        }
+    }
+
+    pub async fn notify_ready(config: &WebhookConfig, name: &str, local_time: bool) {
+        if !config.send_ready {
+            return;
+        }
+
+        let subject = format!("{} - Minecraft Server Ready", name);
+        let body = format!(
+            "On {}, the Minecraft server \"{}\" finished starting.",
+            format_time(Utc::now(), local_time),
+            name
+        );
+
+        if let Err(err) = Self::send(config, &subject, &body, Severity::Info).await {
+            error!("[WEBHOOK] Failed to send ready notification:\n{}", err);
+        }
+    }
+
+    pub async fn start(
+        config: WebhookConfig,
+        name: String,
+        local_time: bool,
+        webhook_rec: Receiver<MailRequest>,
+    ) {
+        while let Ok(request) = webhook_rec.recv().await {
+            if request.warning {
+                let subject = format!("{} - Server Manager warning", name);
+                let body = format!(
+                    "On {}, the Minecraft server manager for \"{}\" raised a warning.\n\n{}",
+                    format_time(request.time, local_time),
+                    name,
+                    request.err_log.join("\n")
+                );
+
+                if let Err(err) = Self::send(&config, &subject, &body, Severity::Warning).await {
+                    error!("[WEBHOOK] Failed to send warning notification:\n{}", err);
+                }
+                continue;
+            }
+
+            let (subject, severity) = if request.final_incident {
+                (
+                    format!("URGENT - {} - Server Manager stopped after incident", name),
+                    Severity::Critical,
+                )
+            } else {
+                (format!("{} - Incident report", name), Severity::Warning)
+            };
+
+            let mut body = format!(
+                "On {}, the Minecraft server \"{}\" encountered an incident.\n\nError report:\n{}",
+                format_time(request.time, local_time),
+                name,
+                request.err_log.join("\n")
+            );
+
+            if request.final_incident {
+                body += "\n\nAfter this incident, the server manager stopped.";
+            }
+
+            if let Err(err) = Self::send(&config, &subject, &body, severity).await {
+                error!("[WEBHOOK] Failed to send incident notification:\n{}", err);
+            }
+
+            if request.final_incident {
+                break;
+            }
+        }
+    }
+
+    async fn send(
+        config: &WebhookConfig,
+        subject: &str,
+        body: &str,
+        severity: Severity,
+    ) -> Result<()> {
+        let encode = |text: &str| match config.content_type {
+            WebhookContentType::Json => {
+                let quoted = json_string(text);
+                quoted[1..quoted.len() - 1].to_string()
+            }
+            WebhookContentType::Form => {
+                url::form_urlencoded::byte_serialize(text.as_bytes()).collect::<String>()
+            }
+        };
+
+        let payload = fill_template(
+            &config.body,
+            &[
+                ("{subject}", encode(subject)),
+                ("{body}", encode(body)),
+                ("{severity}", encode(severity.as_str())),
+            ],
+        );
+
+        let mut headers = config.headers.clone();
+        if !headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+        {
+            let content_type = match config.content_type {
+                WebhookContentType::Json => "application/json",
+                WebhookContentType::Form => "application/x-www-form-urlencoded",
+            };
+            headers.push(("Content-Type".into(), content_type.into()));
+        }
+
+        let mut attempts = 0;
+        loop {
+            match Curl::request(&config.method, config.url.as_str(), &headers, &payload).await {
+                Ok(()) => return Ok(()),
+                Err(err) if attempts >= 4 => return Err(err),
+                Err(_) => {
+                    async_std::task::sleep(Duration::from_secs(2u64.pow(attempts + 1))).await;
+                    attempts += 1;
+                }
+            }
+        }
+    }
+}
+
+// Replaces placeholders in a single pass, so that inserted values containing
+// placeholders are left as they are.
+pub fn fill_template(template: &str, values: &[(&str, String)]) -> String {
+    let mut res = String::with_capacity(template.len());
+    let mut rest = template;
+    'outer: while !rest.is_empty() {
+        for (placeholder, value) in values {
+            if let Some(after) = rest.strip_prefix(placeholder) {
+                res.push_str(value);
+                rest = after;
+                continue 'outer;
+            }
+        }
+
+        let mut chars = rest.chars();
+        if let Some(c) = chars.next() {
+            res.push(c);
+        }
+        rest = chars.as_str();
+    }
+    res
+}